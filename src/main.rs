@@ -1,127 +1,2454 @@
-use std::{convert::Infallible, env};
+use std::{borrow::Cow, collections::{HashMap, HashSet}, convert::{Infallible, TryFrom}, env, fmt, future::Future, str::FromStr, sync::{Arc, Mutex}, time::{Duration, Instant}};
 
-use serde::{Deserialize, Serialize, Serializer, ser::SerializeMap};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::{self, DeserializeOwned, IgnoredAny, MapAccess, SeqAccess, Visitor}, ser::{SerializeMap, SerializeSeq}};
 
-use serde_json::Value;
+use serde_bytes::ByteBuf;
 
-use warp::{Filter, Reply, hyper::StatusCode};
+use serde_json::{Value, json};
 
-use mysql_async::{FromRowError, Params, Pool, Row, prelude::{FromRow, Queryable}};
+use warp::{Filter, Reply, hyper::{Body, StatusCode, body::Bytes}};
+
+use mysql_async::{Column, Conn, DriverError, from_value_opt, chrono::{self, Datelike, NaiveDate, NaiveDateTime, Timelike}, Opts, OptsBuilder, Params, Pool, PoolConstraints, PoolOpts, Row, consts::{ColumnFlags, ColumnType}, prelude::{Protocol, Query as _, Queryable, WithParams}, IsolationLevel, Transaction, TxOpts};
+
+use futures_util::{Stream, StreamExt, stream};
+
+use arrow::{
+    array::{ArrayRef, BinaryBuilder, Date32Builder, Decimal128Builder, DurationMicrosecondBuilder, Float64Builder, Int64Builder, StringBuilder, TimestampMicrosecondBuilder, UInt64Builder},
+    datatypes::{DataType, Date32Type, Field, Schema, SchemaRef, TimeUnit},
+    error::ArrowError,
+    ipc::writer::StreamWriter,
+    record_batch::RecordBatch,
+};
+
+use mysql_common::named_params::parse_named_params;
 
 use once_cell::sync::Lazy;
 
-use log::error;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use log::{error, warn};
+
+static OPTS: Lazy<Opts> = Lazy::new(|| Opts::from_url(&env::var("DATABASE_URL").expect("Missing env var DATABASE_URL")).expect("Invalid env var DATABASE_URL"));
+
+pub static POOL: Lazy<Pool> = Lazy::new(|| Pool::new(OPTS.clone()));
+
+// one pool per requested database, so a pooled connection never comes back with another default database
+static DATABASE_POOLS: Lazy<Mutex<HashMap<String, Pool>>> = Lazy::new(Default::default);
 
-pub static POOL: Lazy<Pool> = Lazy::new(|| Pool::new(env::var("DATABASE_URL").expect("Missing env var DATABASE_URL").as_str()));
+// database pools are never evicted, so there are few of them and they're small
+static DATABASE_POOLS_MAX: Lazy<usize> = Lazy::new(|| env_or("DATABASE_POOLS_MAX", 8));
 
+static DATABASE_POOL_SIZE: Lazy<usize> = Lazy::new(|| Some(env_or("DATABASE_POOL_SIZE", 4)).filter(|&size| size > 0).expect("Invalid env var DATABASE_POOL_SIZE"));
+
+static DATABASE_POOL_OPTS: Lazy<PoolOpts> = Lazy::new(|| OPTS.pool_opts().clone().with_constraints(PoolConstraints::new(0, *DATABASE_POOL_SIZE).unwrap()));
+
+// MessagePack and CBOR bodies can carry raw bytes, that JSON values can't hold
 #[derive(Deserialize)]
 #[serde(untagged)]
+enum Param {
+    Json(Value),
+    Bytes(ByteBuf),
+}
+
+enum ObjectParams {
+    Positional(Vec<Param>),
+    Named(HashMap<String, Param>),
+}
+
+// legacy bodies are a bare SQL string or an `[sql, params]` pair, newer ones a `{version, sql, params, options}` object
 enum Query {
     Simple(String),
-    Prepared((String, Vec<Value>)),
+    Prepared((String, Vec<Param>)),
+    Object {
+        sql: String,
+        params: Option<ObjectParams>,
+        options: Option<CallParams>,
+    },
+}
+
+impl Query {
+    fn take_options(&mut self) -> Option<CallParams> {
+        match self {
+            Query::Object { options, .. } => options.take(),
+            _ => None,
+        }
+    }
+
+    fn into_parts(self) -> Result<(String, Option<Params>), QueryError> {
+        match self {
+            Query::Simple(q) => Ok((q, None)),
+            Query::Prepared((q, p)) => Ok((q, Some(convert_params(p)?))),
+            Query::Object { sql, params, .. } => match params {
+                None => Ok((sql, None)),
+                Some(ObjectParams::Positional(p)) => Ok((sql, Some(convert_params(p)?))),
+                Some(ObjectParams::Named(p)) => {
+                    let params = convert_named_params(&sql, p)?;
+                    Ok((sql, Some(params)))
+                },
+            },
+        }
+    }
+}
+
+// errors are prefixed with the offending field, which serde doesn't do on its own
+fn field<'de, A: MapAccess<'de>, T: Deserialize<'de>>(map: &mut A, path: &str) -> Result<T, A::Error> {
+    map.next_value().map_err(|e| de::Error::custom(format_args!("`{}`: {}", path, e)))
+}
+
+fn element<'de, A: SeqAccess<'de>, T: Deserialize<'de>>(seq: &mut A, index: usize, expected: &dyn de::Expected) -> Result<T, A::Error> {
+    seq.next_element()
+        .map_err(|e| de::Error::custom(format_args!("`[{}]`: {}", index, e)))?
+        .ok_or_else(|| de::Error::invalid_length(index, expected))
+}
+
+const BODY_VERSION: u64 = 1;
+
+impl<'de> Deserialize<'de> for Query {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct QueryVisitor;
+
+        impl<'de> Visitor<'de> for QueryVisitor {
+            type Value = Query;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an SQL string, an [sql, params] array or a {sql, params, options} object")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Query, E> {
+                Ok(Query::Simple(v.to_owned()))
+            }
+
+            fn visit_string<E: de::Error>(self, v: String) -> Result<Query, E> {
+                Ok(Query::Simple(v))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Query, A::Error> {
+                let sql = element(&mut seq, 0, &self)?;
+                let params = element(&mut seq, 1, &self)?;
+                if seq.next_element::<IgnoredAny>()?.is_some() {
+                    return Err(de::Error::invalid_length(3, &self));
+                }
+                Ok(Query::Prepared((sql, params)))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Query, A::Error> {
+                let (mut sql, mut params, mut options) = (None, None, None);
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "version" => {
+                            let version: u64 = field(&mut map, "version")?;
+                            if version != BODY_VERSION {
+                                return Err(de::Error::custom(format_args!("`version`: unsupported version {}, expected {}", version, BODY_VERSION)));
+                            }
+                        },
+                        "sql" => sql = Some(field(&mut map, "sql")?),
+                        "params" => params = field::<_, Option<ObjectParams>>(&mut map, "params")?,
+                        // option errors carry their own `options.<name>` prefix
+                        "options" => options = map.next_value::<Option<BodyOptions>>()?.map(|o| o.0),
+                        _ => return Err(de::Error::custom(format_args!("`{}`: unknown field, expected one of `version`, `sql`, `params`, `options`", key))),
+                    }
+                }
+                let sql = sql.ok_or_else(|| de::Error::custom("`sql`: missing field"))?;
+                Ok(Query::Object { sql, params, options })
+            }
+        }
+
+        deserializer.deserialize_any(QueryVisitor)
+    }
+}
+
+// the body of a batch: an array of queries, in any of the single query shapes
+struct Batch(Vec<Query>);
+
+impl<'de> Deserialize<'de> for Batch {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BatchVisitor;
+
+        impl<'de> Visitor<'de> for BatchVisitor {
+            type Value = Batch;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an array of queries")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Batch, A::Error> {
+                let mut queries = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(256));
+                loop {
+                    let index = queries.len();
+                    match seq.next_element().map_err(|e| de::Error::custom(format_args!("`[{}]`: {}", index, e)))? {
+                        Some(query) => queries.push(query),
+                        None => return Ok(Batch(queries)),
+                    }
+                }
+            }
+        }
+
+        deserializer.deserialize_seq(BatchVisitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for ObjectParams {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ParamsVisitor;
+
+        impl<'de> Visitor<'de> for ParamsVisitor {
+            type Value = ObjectParams;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an array of positional parameters or an object of named parameters")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<ObjectParams, A::Error> {
+                Deserialize::deserialize(de::value::SeqAccessDeserializer::new(seq)).map(ObjectParams::Positional)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<ObjectParams, A::Error> {
+                Deserialize::deserialize(de::value::MapAccessDeserializer::new(map)).map(ObjectParams::Named)
+            }
+        }
+
+        deserializer.deserialize_any(ParamsVisitor)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Encoding {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    fn from_mime(mime: &str) -> Option<Self> {
+        match mime {
+            "application/json" => Some(Encoding::Json),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => Some(Encoding::MessagePack),
+            "application/cbor" => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    // a missing Content-Type is JSON, as it always has been
+    fn from_content_type(content_type: &Option<String>) -> Option<Self> {
+        match content_type.as_deref().and_then(|ct| ct.split(';').next()).map(str::trim) {
+            None => Some(Encoding::Json),
+            Some(mime) => Encoding::from_mime(mime),
+        }
+    }
+
+    fn from_accept(accept: &Option<String>) -> Self {
+        accept.as_deref()
+            .and_then(|a| a.split(',').filter_map(|m| m.split(';').next()).find_map(|m| Encoding::from_mime(m.trim())))
+            .unwrap_or(Encoding::Json)
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Json => "application/json",
+            Encoding::MessagePack => "application/msgpack",
+            Encoding::Cbor => "application/cbor",
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(self, body: &[u8]) -> Result<T, String> {
+        match self {
+            Encoding::Json => serde_json::from_slice(body).map_err(|e| e.to_string()),
+            Encoding::MessagePack => rmp_serde::from_slice(body).map_err(|e| e.to_string()),
+            Encoding::Cbor => ciborium::de::from_reader(body).map_err(|e| e.to_string()),
+        }
+    }
+
+    fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Encoding::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            Encoding::MessagePack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string()),
+            Encoding::Cbor => {
+                let mut body = Vec::new();
+                ciborium::ser::into_writer(value, &mut body).map_err(|e| e.to_string())?;
+                Ok(body)
+            },
+        }
+    }
+
+    fn reply<T: Serialize>(self, value: &T, status: StatusCode) -> warp::reply::Response {
+        match self.encode(value) {
+            Ok(body) => warp::reply::with_status(warp::reply::with_header(body, "content-type", self.content_type()), status).into_response(),
+            Err(e) => {
+                error!("Response encoding error: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Isolation {
+    ReadUncommitted,
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+impl From<Isolation> for IsolationLevel {
+    fn from(isolation: Isolation) -> Self {
+        match isolation {
+            Isolation::ReadUncommitted => IsolationLevel::ReadUncommitted,
+            Isolation::ReadCommitted => IsolationLevel::ReadCommitted,
+            Isolation::RepeatableRead => IsolationLevel::RepeatableRead,
+            Isolation::Serializable => IsolationLevel::Serializable,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum OnError {
+    #[default]
+    Abort,
+    RollbackToSavepoint,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DateFormat {
+    #[default]
+    Mysql,
+    Iso,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum BinaryFormat {
+    #[default]
+    Base64,
+    Hex,
+}
+
+impl BinaryFormat {
+    fn encode(self, bytes: &[u8]) -> String {
+        match self {
+            BinaryFormat::Base64 => base64::encode(bytes),
+            BinaryFormat::Hex => hex::encode(bytes),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum NumberFormat {
+    #[default]
+    Native,
+    Safe,
+    String,
+}
+
+// Number.MAX_SAFE_INTEGER
+const MAX_SAFE_INTEGER: u64 = 9_007_199_254_740_991;
+
+impl NumberFormat {
+    fn serialize_i64<S: Serializer>(self, i: i64, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            NumberFormat::Native => serializer.serialize_i64(i),
+            NumberFormat::Safe if i.unsigned_abs() <= MAX_SAFE_INTEGER => serializer.serialize_i64(i),
+            _ => serializer.serialize_str(&i.to_string()),
+        }
+    }
+
+    fn serialize_u64<S: Serializer>(self, u: u64, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            NumberFormat::Native => serializer.serialize_u64(u),
+            NumberFormat::Safe if u <= MAX_SAFE_INTEGER => serializer.serialize_u64(u),
+            _ => serializer.serialize_str(&u.to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DecimalFormat {
+    #[default]
+    String,
+    Number,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DuplicatePolicy {
+    Qualify,
+    #[default]
+    Suffix,
+    Error,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ResultFormat {
+    #[default]
+    Objects,
+    Compact,
+    Columnar,
+    Csv,
+    Tsv,
+    Arrow,
+}
+
+#[derive(Clone, Copy)]
+enum StreamFormat {
+    Ndjson,
+    Csv,
+    Tsv,
+    Arrow,
+}
+
+impl StreamFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            StreamFormat::Ndjson => "application/x-ndjson",
+            StreamFormat::Csv => "text/csv; charset=utf-8",
+            StreamFormat::Tsv => "text/tab-separated-values; charset=utf-8",
+            StreamFormat::Arrow => "application/vnd.apache.arrow.stream",
+        }
+    }
+}
+
+#[derive(Default)]
+struct FormatOptions {
+    dates: DateFormat,
+    tz: Option<String>,
+    binary: BinaryFormat,
+    numbers: NumberFormat,
+    decimals: DecimalFormat,
+    meta: bool,
+    duplicates: DuplicatePolicy,
+    format: ResultFormat,
+    show_warnings: bool,
+    multi: bool,
 }
 
-struct QueryRow(Row);
+struct QueryRow<'a> {
+    row: &'a Row,
+    keys: &'a [String],
+    options: &'a FormatOptions,
+}
+
+struct QueryValue<'a> {
+    value: Option<&'a mysql_async::Value>,
+    column: &'a Column,
+    options: &'a FormatOptions,
+}
+
+// charset 63 is `binary`, numeric columns share it in the text protocol so the type has to be checked too
+fn is_binary(column: &Column) -> bool {
+    column.character_set() == 63
+        && column.flags().contains(ColumnFlags::BINARY_FLAG)
+        && matches!(
+            column.column_type(),
+            ColumnType::MYSQL_TYPE_STRING | ColumnType::MYSQL_TYPE_VAR_STRING | ColumnType::MYSQL_TYPE_VARCHAR | ColumnType::MYSQL_TYPE_BIT | ColumnType::MYSQL_TYPE_GEOMETRY |
+            ColumnType::MYSQL_TYPE_TINY_BLOB | ColumnType::MYSQL_TYPE_MEDIUM_BLOB | ColumnType::MYSQL_TYPE_LONG_BLOB | ColumnType::MYSQL_TYPE_BLOB
+        )
+}
+
+fn is_integer(column: &Column) -> bool {
+    matches!(
+        column.column_type(),
+        ColumnType::MYSQL_TYPE_TINY | ColumnType::MYSQL_TYPE_SHORT | ColumnType::MYSQL_TYPE_INT24 | ColumnType::MYSQL_TYPE_LONG | ColumnType::MYSQL_TYPE_LONGLONG | ColumnType::MYSQL_TYPE_YEAR
+    )
+}
+
+fn is_float(column: &Column) -> bool {
+    matches!(column.column_type(), ColumnType::MYSQL_TYPE_FLOAT | ColumnType::MYSQL_TYPE_DOUBLE)
+}
+
+fn is_decimal(column: &Column) -> bool {
+    matches!(column.column_type(), ColumnType::MYSQL_TYPE_DECIMAL | ColumnType::MYSQL_TYPE_NEWDECIMAL)
+}
+
+fn is_date_only(column: &Column) -> bool {
+    matches!(column.column_type(), ColumnType::MYSQL_TYPE_DATE | ColumnType::MYSQL_TYPE_NEWDATE)
+}
+
+fn is_datetime(column: &Column) -> bool {
+    matches!(column.column_type(), ColumnType::MYSQL_TYPE_DATETIME | ColumnType::MYSQL_TYPE_DATETIME2 | ColumnType::MYSQL_TYPE_TIMESTAMP | ColumnType::MYSQL_TYPE_TIMESTAMP2)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn format_date(year: u16, month: u8, day: u8, hours: u8, minutes: u8, seconds: u8, micros: u32, column: &Column, options: &FormatOptions) -> String {
+    let date = format!("{:04}-{:02}-{:02}", year, month, day);
+    if is_date_only(column) {
+        return date;
+    }
+
+    let fraction = format_fraction(micros, column);
+    match options.dates {
+        DateFormat::Mysql => format!("{} {:02}:{:02}:{:02}{}", date, hours, minutes, seconds, fraction),
+        DateFormat::Iso => format!("{}T{:02}:{:02}:{:02}{}{}", date, hours, minutes, seconds, fraction, options.tz.as_deref().unwrap_or_default()),
+    }
+}
 
-impl FromRow for QueryRow {
-    fn from_row_opt(row: mysql_async::Row) -> Result<Self, FromRowError> {
-        Ok(QueryRow(row))
+// MySQL prints as many fractional digits as the column precision, expressions report 31 (NOT_FIXED_DEC)
+fn format_fraction(micros: u32, column: &Column) -> String {
+    let digits = match column.decimals() {
+        d @ 1..=6 => d as usize,
+        _ if micros > 0 => 6,
+        _ => 0,
+    };
+    if digits > 0 {
+        format!(".{:0width$}", micros / 10u32.pow(6 - digits as u32), width = digits)
     }
+    else {
+        String::new()
+    }
+}
+
+fn format_time(is_neg: bool, days: u32, hours: u8, minutes: u8, seconds: u8, micros: u32, column: &Column) -> String {
+    let sign = if is_neg { "-" } else { "" };
+    let hours = u64::from(days) * 24 + u64::from(hours);
+    format!("{}{:02}:{:02}:{:02}{}", sign, hours, minutes, seconds, format_fraction(micros, column))
 }
 
-impl Serialize for QueryRow {
+impl<'a> Serialize for QueryValue<'a> {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        let columns = self.0.columns_ref();
-        let mut map = serializer.serialize_map(Some(columns.len()))?;
+        match self.value {
+            // text protocol already returns dates in MySQL format
+            Some(mysql_async::Value::Bytes(a)) if matches!(self.options.dates, DateFormat::Iso) && is_datetime(self.column) => {
+                serializer.serialize_str(&format!("{}{}", String::from_utf8_lossy(a).replacen(' ', "T", 1), self.options.tz.as_deref().unwrap_or_default()))
+            },
+            Some(mysql_async::Value::Bytes(a)) if self.column.column_type() == ColumnType::MYSQL_TYPE_JSON => match serde_json::from_slice::<Value>(a) {
+                Ok(v) => v.serialize(serializer),
+                Err(_) => serializer.serialize_str(&String::from_utf8_lossy(a)),
+            },
+            Some(mysql_async::Value::Bytes(a)) if is_binary(self.column) && !serializer.is_human_readable() => serializer.serialize_bytes(a),
+            Some(mysql_async::Value::Bytes(a)) if is_binary(self.column) => serializer.serialize_str(&self.options.binary.encode(a)),
+            // text protocol returns numbers as strings too, parse them so that both protocols give the same output
+            Some(mysql_async::Value::Bytes(a)) if is_integer(self.column) => {
+                let text = String::from_utf8_lossy(a);
+                if let Ok(i) = text.parse::<i64>() {
+                    self.options.numbers.serialize_i64(i, serializer)
+                }
+                else if let Ok(u) = text.parse::<u64>() {
+                    self.options.numbers.serialize_u64(u, serializer)
+                }
+                else {
+                    serializer.serialize_str(&text)
+                }
+            },
+            Some(mysql_async::Value::Bytes(a)) if is_float(self.column) => {
+                let text = String::from_utf8_lossy(a);
+                match text.parse::<f64>() {
+                    Ok(f) => serializer.serialize_f64(f),
+                    Err(_) => serializer.serialize_str(&text),
+                }
+            },
+            Some(mysql_async::Value::Bytes(a)) if is_decimal(self.column) => {
+                let text = String::from_utf8_lossy(a);
+                match (self.options.numbers, self.options.decimals, text.parse::<f64>()) {
+                    (NumberFormat::Native | NumberFormat::Safe, DecimalFormat::Number, Ok(f)) => serializer.serialize_f64(f),
+                    _ => serializer.serialize_str(&text),
+                }
+            },
+            Some(mysql_async::Value::Bytes(a)) => serializer.serialize_str(&String::from_utf8_lossy(a)),
+            Some(mysql_async::Value::Date(y, m, d, h, i, s, u)) => serializer.serialize_str(&format_date(*y, *m, *d, *h, *i, *s, *u, self.column, self.options)),
+            Some(mysql_async::Value::Double(f)) => serializer.serialize_f64(*f),
+            Some(mysql_async::Value::Float(f)) => serializer.serialize_f32(*f),
+            Some(mysql_async::Value::Int(f)) => self.options.numbers.serialize_i64(*f, serializer),
+            Some(mysql_async::Value::UInt(f)) => self.options.numbers.serialize_u64(*f, serializer),
+            Some(mysql_async::Value::Time(is_neg, d, h, i, s, u)) => serializer.serialize_str(&format_time(*is_neg, *d, *h, *i, *s, *u, self.column)),
+            _ => serializer.serialize_unit(),
+        }
+    }
+}
 
-        for (index, c) in columns.iter().enumerate() {
-            match self.0.as_ref(index) {
-                Some(mysql_async::Value::Bytes(a)) => map.serialize_entry(&c.name_str(), &String::from_utf8_lossy(a))?,
-                Some(mysql_async::Value::Date(y, m, d, h, i, s, u)) => map.serialize_entry(&c.name_str(), &format!("{}-{}-{} {}:{}:{}.{}", y, m, d, h, i, s, u))?,
-                Some(mysql_async::Value::Double(f)) => map.serialize_entry(&c.name_str(), f)?,
-                Some(mysql_async::Value::Float(f)) => map.serialize_entry(&c.name_str(), f)?,
-                Some(mysql_async::Value::Int(f)) => map.serialize_entry(&c.name_str(), f)?,
-                Some(mysql_async::Value::UInt(f)) => map.serialize_entry(&c.name_str(), f)?,
-                // Some(mysql_async::Value::Time(is_neg, d, h, m, s, u)) => Value::from(format!("{}-{}-{} {}:{}:{}.{}", y, m, d, h, i, s, u)),
-                _ => map.serialize_entry(&c.name_str(), &Value::Null)?,
+fn column_keys(columns: &[Column], policy: DuplicatePolicy) -> Result<Vec<String>, QueryError> {
+    let names = columns.iter().map(|c| c.name_str().into_owned()).collect::<Vec<_>>();
+    let mut counts = HashMap::new();
+    for name in &names {
+        *counts.entry(name.as_str()).or_insert(0) += 1;
+    }
+
+    let mut duplicates = counts.iter().filter(|(_, count)| **count > 1).map(|(name, _)| *name).collect::<Vec<_>>();
+    if duplicates.is_empty() {
+        return Ok(names);
+    }
+    duplicates.sort_unstable();
+
+    let candidates = match policy {
+        DuplicatePolicy::Error => return Err(QueryError::result(format!("Duplicate column names: {}", duplicates.join(", ")))),
+        DuplicatePolicy::Qualify => columns.iter().zip(&names).map(|(c, name)| {
+            let table = c.table_str();
+            if counts[name.as_str()] > 1 && !table.is_empty() {
+                format!("{}.{}", table, name)
+            }
+            else {
+                name.clone()
             }
+        }).collect::<Vec<_>>(),
+        DuplicatePolicy::Suffix => names.clone(),
+    };
+
+    // whatever still collides (e.g. expressions without a table) gets a numeric suffix
+    let mut keys = Vec::with_capacity(candidates.len());
+    let mut taken = HashSet::new();
+    for candidate in candidates {
+        let mut key = candidate.clone();
+        let mut n = 1;
+        while (key != candidate && names.contains(&key)) || !taken.insert(key.clone()) {
+            n += 1;
+            key = format!("{}_{}", candidate, n);
+        }
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+impl<'a> Serialize for QueryRow<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let columns = self.row.columns_ref();
+        let mut map = serializer.serialize_map(Some(columns.len()))?;
+
+        for ((index, c), key) in columns.iter().enumerate().zip(self.keys.iter()) {
+            map.serialize_entry(key, &QueryValue { value: self.row.as_ref(index), column: c, options: self.options })?;
         }
 
         map.end()
     }
 }
 
-fn convert_params(params: Vec<Value>) -> Params {
-    Params::Positional(params.into_iter().map(|p| match p {
+struct RowValues<'a> {
+    row: &'a Row,
+    options: &'a FormatOptions,
+}
+
+impl<'a> Serialize for RowValues<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let columns = self.row.columns_ref();
+        let mut seq = serializer.serialize_seq(Some(columns.len()))?;
+
+        for (index, c) in columns.iter().enumerate() {
+            seq.serialize_element(&QueryValue { value: self.row.as_ref(index), column: c, options: self.options })?;
+        }
+
+        seq.end()
+    }
+}
+
+struct ColumnValues<'a> {
+    rows: &'a [Row],
+    index: usize,
+    column: &'a Column,
+    options: &'a FormatOptions,
+}
+
+impl<'a> Serialize for ColumnValues<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.rows.len()))?;
+
+        for row in self.rows {
+            seq.serialize_element(&QueryValue { value: row.as_ref(self.index), column: self.column, options: self.options })?;
+        }
+
+        seq.end()
+    }
+}
+
+fn convert_value(value: Value) -> Result<mysql_async::Value, QueryError> {
+    Ok(match value {
         Value::Bool(b) => mysql_async::Value::from(b),
         Value::String(s) => mysql_async::Value::from(s),
         Value::Number(n) => {
-            if let Some(f) = n.as_f64() {
-                mysql_async::Value::from(f)
+            if let Some(i) = n.as_i64() {
+                mysql_async::Value::from(i)
             }
             else if let Some(u) = n.as_u64() {
                 mysql_async::Value::from(u)
             }
-            else if let Some(i) = n.as_u64() {
-                mysql_async::Value::from(i)
+            else if let Some(f) = n.as_f64() {
+                mysql_async::Value::from(f)
             }
             else {
                 mysql_async::Value::NULL
             }
         },
-        _ => mysql_async::Value::NULL,
-    }).collect())
+        Value::Object(mut o) if o.contains_key("type") => match o.remove("type") {
+            Some(Value::String(kind)) => convert_typed(&kind, o)?,
+            _ => return Err(QueryError::bind("Param type must be a string".to_owned())),
+        },
+        // binary params use the same encodings as binary columns: `{"base64": "..."}` or `{"hex": "..."}`
+        Value::Object(o) if o.len() == 1 => match o.into_iter().next() {
+            Some((k, Value::String(s))) if k == "base64" => mysql_async::Value::Bytes(base64::decode(&s).map_err(|e| QueryError::bind(format!("Invalid base64 param: {}", e)))?),
+            Some((k, Value::String(s))) if k == "hex" => mysql_async::Value::Bytes(hex::decode(&s).map_err(|e| QueryError::bind(format!("Invalid hex param: {}", e)))?),
+            _ => return Err(invalid_param()),
+        },
+        Value::Object(_) | Value::Array(_) => return Err(invalid_param()),
+        Value::Null => mysql_async::Value::NULL,
+    })
 }
 
-#[derive(Serialize)]
-#[serde(untagged)]
-enum QueryResult {
-    Id(Option<u64>),
-    Rows(Vec<QueryRow>),
+fn invalid_param() -> QueryError {
+    QueryError::bind(r#"Invalid param, objects must be `{"base64": "..."}`, `{"hex": "..."}` or `{"type": "...", ...}`"#.to_owned())
 }
 
-fn error<E: std::error::Error>(prefix: &str, e: E) -> String {
-    error!("{}: {}", prefix, e);
-    e.to_string()
+fn typed_text(kind: &str, o: &mut serde_json::Map<String, Value>, key: &str) -> Result<String, QueryError> {
+    match o.remove(key) {
+        Some(Value::String(s)) => Ok(s),
+        _ => Err(QueryError::bind(format!("Param of type `{}` needs a string `{}`", kind, key))),
+    }
 }
 
-async fn _query(query: Query, get_id: bool) -> Result<QueryResult, String> {
-    let mut conn = POOL.get_conn().await.map_err(|e| error("MySQL connection error", e))?;
-    if get_id {
-        match query {
-            Query::Simple(q) => Ok(QueryResult::Id(conn.query_iter(q).await.map_err(|e| error("MySQL query error", e))?.last_insert_id())),
-            Query::Prepared((q, p)) => Ok(QueryResult::Id(conn.exec_iter(q, convert_params(p)).await.map_err(|e| error("MySQL query error", e))?.last_insert_id())),
-        }
+// accepts what `dates=mysql` and `dates=iso` print without a `tz`, the session time zone isn't known so offsets are refused
+fn parse_datetime(text: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.f").ok()
+        .or_else(|| NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%.f").ok())
+        .or_else(|| NaiveDate::parse_from_str(text, "%Y-%m-%d").ok().and_then(|d| d.and_hms_opt(0, 0, 0)))
+}
+
+// `[-]HHH:MM:SS[.ffffff]`, hours may exceed 24 as in MySQL's TIME
+fn parse_time(text: &str) -> Option<mysql_async::Value> {
+    let (is_neg, text) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let (text, fraction) = match text.split_once('.') {
+        Some((text, fraction)) if (1..=6).contains(&fraction.len()) && fraction.bytes().all(|b| b.is_ascii_digit()) => (text, fraction),
+        Some(_) => return None,
+        None => (text, ""),
+    };
+    let mut parts = text.split(':').map(|p| p.parse::<u32>().ok().filter(|_| p.bytes().all(|b| b.is_ascii_digit())));
+    let (hours, minutes, seconds) = match (parts.next()??, parts.next()??, parts.next()??, parts.next()) {
+        (h, m, s, None) if m < 60 && s < 60 => (h, m, s),
+        _ => return None,
+    };
+    let micros = if fraction.is_empty() { 0 } else { format!("{:0<6}", fraction).parse().ok()? };
+    Some(mysql_async::Value::Time(is_neg, hours / 24, (hours % 24) as u8, minutes as u8, seconds as u8, micros))
+}
+
+fn is_decimal_text(text: &str) -> bool {
+    let digits = text.strip_prefix(|c| c == '-' || c == '+').unwrap_or(text);
+    let (int, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    !(int.is_empty() && fraction.is_empty()) && int.bytes().chain(fraction.bytes()).all(|b| b.is_ascii_digit())
+}
+
+// `{"type": "...", ...}` params for values whose JSON shape doesn't say enough
+fn convert_typed(kind: &str, mut o: serde_json::Map<String, Value>) -> Result<mysql_async::Value, QueryError> {
+    let invalid = |text: &str| QueryError::bind(format!("Invalid {} param: `{}`", kind, text));
+    match kind {
+        "datetime" | "timestamp" => {
+            let text = typed_text(kind, &mut o, "value")?;
+            let d = parse_datetime(&text).ok_or_else(|| invalid(&text))?;
+            let year = u16::try_from(d.year()).map_err(|_| invalid(&text))?;
+            Ok(mysql_async::Value::Date(year, d.month() as u8, d.day() as u8, d.hour() as u8, d.minute() as u8, d.second() as u8, d.nanosecond() / 1000))
+        },
+        "date" => {
+            let text = typed_text(kind, &mut o, "value")?;
+            let d = NaiveDate::parse_from_str(&text, "%Y-%m-%d").map_err(|_| invalid(&text))?;
+            let year = u16::try_from(d.year()).map_err(|_| invalid(&text))?;
+            Ok(mysql_async::Value::Date(year, d.month() as u8, d.day() as u8, 0, 0, 0, 0))
+        },
+        "time" => {
+            let text = typed_text(kind, &mut o, "value")?;
+            parse_time(&text).ok_or_else(|| invalid(&text))
+        },
+        // MySQL sends and takes decimals as text, so the digits go through untouched
+        "decimal" => {
+            let text = typed_text(kind, &mut o, "value")?;
+            if !is_decimal_text(&text) {
+                return Err(invalid(&text));
+            }
+            Ok(mysql_async::Value::Bytes(text.into_bytes()))
+        },
+        "blob" | "binary" => match (o.remove("base64"), o.remove("hex")) {
+            (Some(Value::String(s)), None) => Ok(mysql_async::Value::Bytes(base64::decode(&s).map_err(|e| QueryError::bind(format!("Invalid base64 param: {}", e)))?)),
+            (None, Some(Value::String(s))) => Ok(mysql_async::Value::Bytes(hex::decode(&s).map_err(|e| QueryError::bind(format!("Invalid hex param: {}", e)))?)),
+            _ => Err(QueryError::bind(format!("Param of type `{}` needs either a string `base64` or a string `hex`", kind))),
+        },
+        // integers past 2^53 can't survive a JavaScript client as numbers
+        "int" => {
+            let text = typed_text(kind, &mut o, "value")?;
+            text.parse::<i64>().map(mysql_async::Value::Int).map_err(|_| invalid(&text))
+        },
+        "uint" => {
+            let text = typed_text(kind, &mut o, "value")?;
+            text.parse::<u64>().map(mysql_async::Value::UInt).map_err(|_| invalid(&text))
+        },
+        _ => Err(QueryError::bind(format!("Unknown param type `{}`, expected one of `datetime`, `timestamp`, `date`, `time`, `decimal`, `blob`, `binary`, `int`, `uint`", kind))),
     }
-    else {
-        match query {
-            Query::Simple(q) => Ok(QueryResult::Rows(conn.query(q).await.map_err(|e| error("MySQL query error", e))?)),
-            Query::Prepared((q, p)) => Ok(QueryResult::Rows(conn.exec(q, convert_params(p)).await.map_err(|e| error("MySQL query error", e))?)),
+}
+
+fn convert_param(param: Param) -> Result<mysql_async::Value, QueryError> {
+    match param {
+        Param::Json(value) => convert_value(value),
+        Param::Bytes(bytes) => Ok(mysql_async::Value::Bytes(bytes.into_vec())),
+    }
+}
+
+fn convert_params(params: Vec<Param>) -> Result<Params, QueryError> {
+    Ok(Params::Positional(params.into_iter().map(convert_param).collect::<Result<_, _>>()?))
+}
+
+// mysql_async only complains about the first missing name and silently ignores extra ones
+fn convert_named_params(sql: &str, params: HashMap<String, Param>) -> Result<Params, QueryError> {
+    let (names, _) = parse_named_params(sql).map_err(|_| QueryError::bind("Named and positional parameters mixed in one statement".to_owned()))?;
+    let names = names.unwrap_or_default().into_iter().collect::<HashSet<_>>();
+
+    let mut missing = names.iter().filter(|n| !params.contains_key(*n)).map(String::as_str).collect::<Vec<_>>();
+    if !missing.is_empty() {
+        missing.sort_unstable();
+        return Err(QueryError::bind(format!("Missing named parameters: {}", missing.join(", "))));
+    }
+    let mut extra = params.keys().filter(|n| !names.contains(*n)).map(String::as_str).collect::<Vec<_>>();
+    if !extra.is_empty() {
+        extra.sort_unstable();
+        return Err(QueryError::bind(format!("Unknown named parameters: {}", extra.join(", "))));
+    }
+
+    Ok(Params::Named(params.into_iter().map(|(name, param)| Ok((name, convert_param(param)?))).collect::<Result<_, QueryError>>()?))
+}
+
+#[derive(Serialize)]
+struct ColumnMeta {
+    key: String,
+    name: String,
+    table: String,
+    org_table: String,
+    org_name: String,
+    #[serde(rename = "type")]
+    column_type: String,
+    nullable: bool,
+    primary_key: bool,
+    unsigned: bool,
+    binary: bool,
+    charset: u16,
+    decimals: u8,
+}
+
+impl ColumnMeta {
+    fn new(c: &Column, key: String) -> Self {
+        let flags = c.flags();
+        ColumnMeta {
+            key,
+            name: c.name_str().into_owned(),
+            table: c.table_str().into_owned(),
+            org_table: c.org_table_str().into_owned(),
+            org_name: c.org_name_str().into_owned(),
+            column_type: format!("{:?}", c.column_type()).trim_start_matches("MYSQL_TYPE_").to_lowercase(),
+            nullable: !flags.contains(ColumnFlags::NOT_NULL_FLAG),
+            primary_key: flags.contains(ColumnFlags::PRI_KEY_FLAG),
+            unsigned: flags.contains(ColumnFlags::UNSIGNED_FLAG),
+            binary: flags.contains(ColumnFlags::BINARY_FLAG),
+            charset: c.character_set(),
+            decimals: c.decimals(),
         }
     }
 }
 
-#[derive(Deserialize)]
-struct CallParams {
-    #[serde(rename = "return")]
-    _return: Option<bool>,
+struct ResultSet {
+    columns: Arc<[Column]>,
+    keys: Vec<String>,
+    rows: Vec<Row>,
+    options: Arc<FormatOptions>,
 }
 
-async fn query(q: Query, params: CallParams) -> Result<impl warp::Reply, Infallible> {
-    Ok(match _query(q, params._return == Some(true)).await {
-        Ok(v) => warp::reply::json(&v).into_response(),
-        Err(e) => warp::reply::with_status(e, StatusCode::INTERNAL_SERVER_ERROR).into_response(),
-    })
+struct ResultColumns<'a> {
+    columns: &'a [Column],
+    keys: &'a [String],
+    options: &'a FormatOptions,
 }
 
-#[tokio::main]
-pub async fn main() {
-    env_logger::init();
+impl<'a> Serialize for ResultColumns<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.keys.len()))?;
 
-    let promote = warp::post()
-        .and(warp::body::json())
-        .and(warp::filters::query::query())
-        .and_then(query);
+        for (c, key) in self.columns.iter().zip(self.keys) {
+            if self.options.meta {
+                seq.serialize_element(&ColumnMeta::new(c, key.clone()))?;
+            }
+            else {
+                seq.serialize_element(key)?;
+            }
+        }
+
+        seq.end()
+    }
+}
 
-    warp::serve(promote).run(([0, 0, 0, 0], 3030)).await;
+struct ResultRows<'a>(&'a ResultSet);
+
+impl<'a> Serialize for ResultRows<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let set = self.0;
+        match set.options.format {
+            // CSV, TSV and Arrow are streamed and batches refuse them, only `return` with `multi` skips the stream and falls back to objects
+            ResultFormat::Objects | ResultFormat::Csv | ResultFormat::Tsv | ResultFormat::Arrow => serializer.collect_seq(set.rows.iter().map(|row| QueryRow { row, keys: &set.keys, options: &set.options })),
+            ResultFormat::Compact => serializer.collect_seq(set.rows.iter().map(|row| RowValues { row, options: &set.options })),
+            ResultFormat::Columnar => serializer.collect_seq(set.columns.iter().enumerate().map(|(index, column)| ColumnValues { rows: &set.rows, index, column, options: &set.options })),
+        }
+    }
+}
+
+impl Serialize for ResultSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.options.format {
+            // with multiple result sets every set needs its own columns
+            ResultFormat::Objects if !self.options.meta && !self.options.multi => ResultRows(self).serialize(serializer),
+            format => {
+                let mut map = serializer.serialize_map(Some(2))?;
+                map.serialize_entry("columns", &ResultColumns { columns: &self.columns, keys: &self.keys, options: &self.options })?;
+                map.serialize_entry(if matches!(format, ResultFormat::Columnar) { "data" } else { "rows" }, &ResultRows(self))?;
+                map.end()
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum QueryResult {
+    Write(WriteSummary),
+    Rows(ResultSet),
+    Sets(Vec<QueryResult>),
+    Skipped(Skipped),
+}
+
+// a batch statement that failed and was rolled back to its savepoint
+#[derive(Serialize)]
+struct Skipped {
+    skipped: bool,
+    error: QueryError,
+}
+
+#[derive(Serialize)]
+struct Warning {
+    level: String,
+    code: u32,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct WriteSummary {
+    affected_rows: u64,
+    last_insert_id: Option<u64>,
+    warnings: u16,
+    info: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning_details: Option<Vec<Warning>>,
+}
+
+impl WriteSummary {
+    fn new<P: Protocol>(result: &mysql_async::QueryResult<'_, '_, P>) -> Self {
+        WriteSummary {
+            affected_rows: result.affected_rows(),
+            last_insert_id: result.last_insert_id(),
+            warnings: result.warnings(),
+            info: result.info().into_owned(),
+            warning_details: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ErrorKind {
+    Connection,
+    Driver,
+    Server,
+    Io,
+    Request,
+    Timeout,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Stage {
+    Connect,
+    Bind,
+    Execute,
+    Result,
+    Timeout,
+    Session,
+    ContentType,
+}
+
+impl FromStr for Stage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "connect" => Ok(Stage::Connect),
+            "bind" => Ok(Stage::Bind),
+            "execute" => Ok(Stage::Execute),
+            "result" => Ok(Stage::Result),
+            "timeout" => Ok(Stage::Timeout),
+            "session" => Ok(Stage::Session),
+            "content_type" => Ok(Stage::ContentType),
+            _ => Err(format!("unknown stage `{}`", s)),
+        }
+    }
+}
+
+struct StatusMap {
+    stages: HashMap<Stage, StatusCode>,
+    codes: HashMap<u16, StatusCode>,
+}
+
+impl StatusMap {
+    fn status(&self, e: &QueryError) -> StatusCode {
+        e.code.and_then(|c| self.codes.get(&c))
+            .or_else(|| self.stages.get(&e.stage))
+            .copied()
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    // overrides are in the form `1062=422,connect=502`
+    fn with_overrides(mut self, overrides: &str) -> Result<Self, String> {
+        for entry in overrides.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (key, status) = entry.split_once('=').ok_or_else(|| format!("invalid entry `{}`", entry))?;
+            let status = status.trim().parse::<u16>().ok()
+                .and_then(|s| StatusCode::from_u16(s).ok())
+                .ok_or_else(|| format!("invalid HTTP status `{}`", status))?;
+            match key.trim().parse::<u16>() {
+                Ok(code) => self.codes.insert(code, status),
+                Err(_) => self.stages.insert(key.trim().parse()?, status),
+            };
+        }
+        Ok(self)
+    }
+}
+
+impl Default for StatusMap {
+    fn default() -> Self {
+        StatusMap {
+            stages: vec![
+                (Stage::Connect, StatusCode::SERVICE_UNAVAILABLE),
+                (Stage::Bind, StatusCode::BAD_REQUEST),
+                (Stage::Result, StatusCode::BAD_REQUEST),
+                (Stage::Timeout, StatusCode::GATEWAY_TIMEOUT),
+                (Stage::Session, StatusCode::NOT_FOUND),
+                (Stage::ContentType, StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            ].into_iter().collect(),
+            codes: vec![
+                // ER_PARSE_ERROR, ER_SYNTAX_ERROR, ER_WRONG_ARGUMENTS
+                (1064, StatusCode::BAD_REQUEST),
+                (1149, StatusCode::BAD_REQUEST),
+                (1210, StatusCode::BAD_REQUEST),
+                // ER_DUP_ENTRY, ER_DUP_ENTRY_WITH_KEY_NAME
+                (1062, StatusCode::CONFLICT),
+                (1586, StatusCode::CONFLICT),
+                // ER_NO_REFERENCED_ROW, ER_ROW_IS_REFERENCED, ER_ROW_IS_REFERENCED_2, ER_NO_REFERENCED_ROW_2
+                (1216, StatusCode::CONFLICT),
+                (1217, StatusCode::CONFLICT),
+                (1451, StatusCode::CONFLICT),
+                (1452, StatusCode::CONFLICT),
+                // ER_NO_SUCH_TABLE
+                (1146, StatusCode::NOT_FOUND),
+                // ER_BAD_DB_ERROR
+                (1049, StatusCode::NOT_FOUND),
+                // ER_LOCK_WAIT_TIMEOUT
+                (1205, StatusCode::GATEWAY_TIMEOUT),
+                // ER_LOCK_DEADLOCK
+                (1213, StatusCode::CONFLICT),
+            ].into_iter().collect(),
+        }
+    }
+}
+
+static STATUS_MAP: Lazy<StatusMap> = Lazy::new(|| {
+    let map = StatusMap::default();
+    match env::var("STATUS_CODES") {
+        Ok(overrides) => map.with_overrides(&overrides).expect("Invalid env var STATUS_CODES"),
+        Err(_) => map,
+    }
+});
+
+#[derive(Serialize)]
+struct QueryError {
+    // the failing statement of a batch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index: Option<usize>,
+    #[serde(skip)]
+    stage: Stage,
+    kind: ErrorKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<String>,
+    message: String,
+}
+
+impl QueryError {
+    fn bind(message: String) -> Self {
+        error!("Param conversion error: {}", message);
+        QueryError { index: None, stage: Stage::Bind, kind: ErrorKind::Request, code: None, state: None, message }
+    }
+
+    fn result(message: String) -> Self {
+        error!("Result error: {}", message);
+        QueryError { index: None, stage: Stage::Result, kind: ErrorKind::Request, code: None, state: None, message }
+    }
+
+    fn timeout(timeout: Duration) -> Self {
+        let message = format!("Query exceeded the {}ms timeout", timeout.as_millis());
+        error!("{}", message);
+        QueryError { index: None, stage: Stage::Timeout, kind: ErrorKind::Timeout, code: None, state: None, message }
+    }
+
+    fn session(id: &str) -> Self {
+        let message = format!("No open transaction `{}`", id);
+        error!("{}", message);
+        QueryError { index: None, stage: Stage::Session, kind: ErrorKind::Request, code: None, state: None, message }
+    }
+
+    fn content_type(content_type: &str) -> Self {
+        let message = format!("Unsupported content type `{}`, expected `application/json`, `application/msgpack` or `application/cbor`", content_type);
+        error!("{}", message);
+        QueryError { index: None, stage: Stage::ContentType, kind: ErrorKind::Request, code: None, state: None, message }
+    }
+
+    fn database_limit() -> Self {
+        let message = "Too many databases in use".to_owned();
+        error!("{}", message);
+        QueryError { index: None, stage: Stage::Connect, kind: ErrorKind::Connection, code: None, state: None, message }
+    }
+
+    fn session_limit() -> Self {
+        let message = "Too many open transactions".to_owned();
+        error!("{}", message);
+        QueryError { index: None, stage: Stage::Connect, kind: ErrorKind::Connection, code: None, state: None, message }
+    }
+
+    // a deadlock rolls the transaction back on the server, a broken connection loses it with the session
+    fn ends_transaction(&self) -> bool {
+        match self.kind {
+            ErrorKind::Io | ErrorKind::Connection => true,
+            ErrorKind::Driver => self.stage == Stage::Execute,
+            _ => self.code == Some(1213),
+        }
+    }
+
+    fn transaction_ended(mut self, id: &str) -> Self {
+        self.message = format!("{}, transaction `{}` was rolled back and is closed", self.message, id);
+        self
+    }
+
+    // deadlocks and lock wait timeouts, both of which can go away on their own
+    fn is_retryable(&self) -> bool {
+        matches!(self.kind, ErrorKind::Server) && matches!(self.code, Some(1205 | 1213))
+    }
+
+    fn at(mut self, index: usize) -> Self {
+        self.index = Some(index);
+        self
+    }
+
+    fn connection(mut self) -> Self {
+        if !matches!(self.kind, ErrorKind::Server) {
+            self.kind = ErrorKind::Connection;
+        }
+        self.stage = Stage::Connect;
+        self
+    }
+}
+
+impl From<mysql_async::Error> for QueryError {
+    fn from(e: mysql_async::Error) -> Self {
+        match e {
+            mysql_async::Error::Server(s) => QueryError { index: None, stage: Stage::Execute, kind: ErrorKind::Server, code: Some(s.code), state: Some(s.state), message: s.message },
+            mysql_async::Error::Io(e) => QueryError { index: None, stage: Stage::Execute, kind: ErrorKind::Io, code: None, state: None, message: e.to_string() },
+            mysql_async::Error::Url(e) => QueryError { index: None, stage: Stage::Connect, kind: ErrorKind::Connection, code: None, state: None, message: e.to_string() },
+            mysql_async::Error::Driver(e @ (DriverError::MissingNamedParam { .. } | DriverError::MixedParams | DriverError::NamedParamsForPositionalQuery | DriverError::StmtParamsMismatch { .. })) => {
+                QueryError { index: None, stage: Stage::Bind, kind: ErrorKind::Driver, code: None, state: None, message: e.to_string() }
+            },
+            e => QueryError { index: None, stage: Stage::Execute, kind: ErrorKind::Driver, code: None, state: None, message: e.to_string() },
+        }
+    }
+}
+
+fn error(prefix: &str, e: mysql_async::Error) -> QueryError {
+    error!("{}: {}", prefix, e);
+    QueryError::from(e)
+}
+
+// columns are taken from the result set rather than from the rows, so they're available even without rows
+enum Collected {
+    Rows(Option<Arc<[Column]>>, Vec<Row>),
+    Write(WriteSummary),
+}
+
+// statements without columns (INSERT, UPDATE, ...) get a summary instead of an empty list of rows
+async fn collect_rows<'a, 't: 'a, P: Protocol>(mut result: mysql_async::QueryResult<'a, 't, P>, summary: bool) -> Result<Collected, mysql_async::Error> {
+    let columns = result.columns();
+    if summary || columns.as_ref().map(|c| c.is_empty()).unwrap_or(true) {
+        let summary = WriteSummary::new(&result);
+        result.drop_result().await?;
+        return Ok(Collected::Write(summary));
+    }
+
+    let rows = result.collect().await?;
+    result.drop_result().await?;
+    Ok(Collected::Rows(columns, rows))
+}
+
+async fn show_warnings<C: Queryable>(conn: &mut C) -> Result<Vec<Warning>, mysql_async::Error> {
+    conn.query_map("SHOW WARNINGS", |(level, code, message)| Warning { level, code, message }).await
+}
+
+async fn collect_sets<'a, 't: 'a, P: Protocol>(mut result: mysql_async::QueryResult<'a, 't, P>) -> Result<Vec<Collected>, mysql_async::Error> {
+    let mut sets = Vec::new();
+    // `columns()` is None once the last result set has been consumed
+    while let Some(columns) = result.columns() {
+        if columns.is_empty() {
+            sets.push(Collected::Write(WriteSummary::new(&result)));
+            result.collect::<Row>().await?;
+        }
+        else {
+            let rows = result.collect().await?;
+            sets.push(Collected::Rows(Some(columns), rows));
+        }
+    }
+    // a failing later statement also ends the loop, its error is only returned from here
+    result.drop_result().await?;
+    Ok(sets)
+}
+
+fn wrap_rows(columns: Option<Arc<[Column]>>, rows: Vec<Row>, options: Arc<FormatOptions>) -> Result<QueryResult, QueryError> {
+    let columns = columns.unwrap_or_else(|| Arc::new([]));
+    let keys = column_keys(&columns, options.duplicates)?;
+    Ok(QueryResult::Rows(ResultSet { columns, keys, rows, options }))
+}
+
+fn ndjson_line<T: Serialize>(value: &T) -> Result<Vec<u8>, serde_json::Error> {
+    let mut line = serde_json::to_vec(value)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+// NULL is `\N` in TSV and an unquoted empty field in CSV, where an empty string is quoted as `""`
+fn push_field(line: &mut Vec<u8>, field: Option<&str>, format: StreamFormat) {
+    let field = match field {
+        Some(field) => field,
+        None => {
+            if let StreamFormat::Tsv = format {
+                line.extend_from_slice(b"\\N");
+            }
+            return;
+        },
+    };
+    match format {
+        StreamFormat::Csv if field.is_empty() => line.extend_from_slice(b"\"\""),
+        // RFC 4180
+        StreamFormat::Csv if field.contains(&[',', '"', '\r', '\n'][..]) => {
+            line.push(b'"');
+            line.extend_from_slice(field.replace('"', "\"\"").as_bytes());
+            line.push(b'"');
+        },
+        // TSV can't quote, use the same escapes as MySQL's SELECT ... INTO OUTFILE
+        StreamFormat::Tsv => line.extend_from_slice(field.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r").as_bytes()),
+        _ => line.extend_from_slice(field.as_bytes()),
+    }
+}
+
+fn delimited_line<'a, I: IntoIterator<Item = Option<Cow<'a, str>>>>(fields: I, format: StreamFormat) -> Vec<u8> {
+    let mut line = Vec::new();
+    for (index, field) in fields.into_iter().enumerate() {
+        if index > 0 {
+            line.push(if matches!(format, StreamFormat::Tsv) { b'\t' } else { b',' });
+        }
+        push_field(&mut line, field.as_deref(), format);
+    }
+    line.extend_from_slice(if matches!(format, StreamFormat::Csv) { b"\r\n" } else { b"\n" });
+    line
+}
+
+// values go through the same conversion as the JSON output, then strings are taken raw
+fn value_text(value: Option<&mysql_async::Value>, column: &Column, options: &FormatOptions) -> Result<Option<String>, serde_json::Error> {
+    Ok(match serde_json::to_value(&QueryValue { value, column, options })? {
+        Value::Null => None,
+        Value::String(s) => Some(s),
+        v => Some(v.to_string()),
+    })
+}
+
+fn delimited_row(row: &Row, options: &FormatOptions, format: StreamFormat) -> Result<Vec<u8>, serde_json::Error> {
+    let fields = row.columns_ref().iter().enumerate()
+        .map(|(index, c)| value_text(row.as_ref(index), c, options).map(|text| text.map(Cow::Owned)))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(delimited_line(fields, format))
+}
+
+const ARROW_BATCH_SIZE: usize = 1024;
+
+fn arrow_type(column: &Column) -> DataType {
+    let unsigned = column.flags().contains(ColumnFlags::UNSIGNED_FLAG);
+    match column.column_type() {
+        _ if is_integer(column) && unsigned => DataType::UInt64,
+        _ if is_integer(column) => DataType::Int64,
+        _ if is_float(column) => DataType::Float64,
+        _ if is_decimal(column) => {
+            // column length counts the sign and the decimal point too
+            let scale = column.decimals();
+            let precision = column.column_length().saturating_sub(u32::from(scale > 0) + u32::from(!unsigned));
+            match u8::try_from(precision) {
+                Ok(precision @ 1..=38) => DataType::Decimal128(precision, scale as i8),
+                _ => DataType::Utf8,
+            }
+        },
+        _ if is_date_only(column) => DataType::Date32,
+        _ if is_datetime(column) => DataType::Timestamp(TimeUnit::Microsecond, None),
+        // TIME is an interval of up to ±838 hours rather than a time of day
+        ColumnType::MYSQL_TYPE_TIME | ColumnType::MYSQL_TYPE_TIME2 => DataType::Duration(TimeUnit::Microsecond),
+        _ if is_binary(column) => DataType::Binary,
+        _ => DataType::Utf8,
+    }
+}
+
+fn parse_decimal(text: &str, scale: i8) -> Option<i128> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let (int, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    let scale = usize::try_from(scale).ok()?;
+    if fraction.len() > scale {
+        return None;
+    }
+    let value = format!("{}{:0<width$}", int, fraction, width = scale).parse::<i128>().ok()?;
+    Some(if negative { -value } else { value })
+}
+
+enum ArrowColumn {
+    Int(Int64Builder),
+    UInt(UInt64Builder),
+    Float(Float64Builder),
+    Decimal(Decimal128Builder, i8),
+    Date(Date32Builder),
+    Timestamp(TimestampMicrosecondBuilder),
+    Time(DurationMicrosecondBuilder),
+    Binary(BinaryBuilder),
+    Text(StringBuilder),
+}
+
+impl ArrowColumn {
+    fn new(data_type: &DataType) -> Result<Self, ArrowError> {
+        Ok(match data_type {
+            DataType::Int64 => ArrowColumn::Int(Int64Builder::with_capacity(ARROW_BATCH_SIZE)),
+            DataType::UInt64 => ArrowColumn::UInt(UInt64Builder::with_capacity(ARROW_BATCH_SIZE)),
+            DataType::Float64 => ArrowColumn::Float(Float64Builder::with_capacity(ARROW_BATCH_SIZE)),
+            DataType::Decimal128(precision, scale) => ArrowColumn::Decimal(Decimal128Builder::with_capacity(ARROW_BATCH_SIZE).with_precision_and_scale(*precision, *scale)?, *scale),
+            DataType::Date32 => ArrowColumn::Date(Date32Builder::with_capacity(ARROW_BATCH_SIZE)),
+            DataType::Timestamp(_, _) => ArrowColumn::Timestamp(TimestampMicrosecondBuilder::with_capacity(ARROW_BATCH_SIZE)),
+            DataType::Duration(_) => ArrowColumn::Time(DurationMicrosecondBuilder::with_capacity(ARROW_BATCH_SIZE)),
+            DataType::Binary => ArrowColumn::Binary(BinaryBuilder::new()),
+            _ => ArrowColumn::Text(StringBuilder::new()),
+        })
+    }
+
+    fn append(&mut self, value: Option<&mysql_async::Value>, column: &Column, options: &FormatOptions) -> Result<(), String> {
+        let value = match value {
+            None | Some(mysql_async::Value::NULL) => {
+                self.append_null();
+                return Ok(());
+            },
+            Some(value) => value,
+        };
+        let invalid = |e: &dyn std::fmt::Display| format!("Can't convert column `{}` to Arrow: {}", column.name_str(), e);
+        match self {
+            ArrowColumn::Int(b) => b.append_value(from_value_opt::<i64>(value.clone()).map_err(|e| invalid(&e))?),
+            ArrowColumn::UInt(b) => b.append_value(from_value_opt::<u64>(value.clone()).map_err(|e| invalid(&e))?),
+            ArrowColumn::Float(b) => b.append_value(from_value_opt::<f64>(value.clone()).map_err(|e| invalid(&e))?),
+            ArrowColumn::Decimal(b, scale) => {
+                let text = from_value_opt::<String>(value.clone()).map_err(|e| invalid(&e))?;
+                b.append_value(parse_decimal(&text, *scale).ok_or_else(|| invalid(&text))?);
+            },
+            // zero dates can't be represented, they become nulls
+            ArrowColumn::Date(b) => b.append_option(from_value_opt::<NaiveDate>(value.clone()).ok().map(Date32Type::from_naive_date)),
+            ArrowColumn::Timestamp(b) => b.append_option(from_value_opt::<NaiveDateTime>(value.clone()).ok().map(|d| d.and_utc().timestamp_micros())),
+            ArrowColumn::Time(b) => b.append_value(from_value_opt::<chrono::Duration>(value.clone()).ok().and_then(|d| d.num_microseconds()).ok_or_else(|| invalid(&"invalid TIME"))?),
+            ArrowColumn::Binary(b) => b.append_value(from_value_opt::<Vec<u8>>(value.clone()).map_err(|e| invalid(&e))?),
+            ArrowColumn::Text(b) => b.append_option(value_text(Some(value), column, options).map_err(|e| invalid(&e))?),
+        }
+        Ok(())
+    }
+
+    fn append_null(&mut self) {
+        match self {
+            ArrowColumn::Int(b) => b.append_null(),
+            ArrowColumn::UInt(b) => b.append_null(),
+            ArrowColumn::Float(b) => b.append_null(),
+            ArrowColumn::Decimal(b, _) => b.append_null(),
+            ArrowColumn::Date(b) => b.append_null(),
+            ArrowColumn::Timestamp(b) => b.append_null(),
+            ArrowColumn::Time(b) => b.append_null(),
+            ArrowColumn::Binary(b) => b.append_null(),
+            ArrowColumn::Text(b) => b.append_null(),
+        }
+    }
+
+    fn finish(self) -> ArrayRef {
+        match self {
+            ArrowColumn::Int(mut b) => Arc::new(b.finish()),
+            ArrowColumn::UInt(mut b) => Arc::new(b.finish()),
+            ArrowColumn::Float(mut b) => Arc::new(b.finish()),
+            ArrowColumn::Decimal(mut b, _) => Arc::new(b.finish()),
+            ArrowColumn::Date(mut b) => Arc::new(b.finish()),
+            ArrowColumn::Timestamp(mut b) => Arc::new(b.finish()),
+            ArrowColumn::Time(mut b) => Arc::new(b.finish()),
+            ArrowColumn::Binary(mut b) => Arc::new(b.finish()),
+            ArrowColumn::Text(mut b) => Arc::new(b.finish()),
+        }
+    }
+}
+
+fn arrow_batch(schema: &SchemaRef, columns: &[Column], rows: Vec<mysql_async::Result<Row>>, options: &FormatOptions) -> Result<RecordBatch, String> {
+    let mut builders = schema.fields().iter().map(|f| ArrowColumn::new(f.data_type())).collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+    for row in rows {
+        let row = row.map_err(|e| error("MySQL stream error", e).message)?;
+        for ((index, column), builder) in columns.iter().enumerate().zip(builders.iter_mut()) {
+            builder.append(row.as_ref(index), column, options)?;
+        }
+    }
+    RecordBatch::try_new(Arc::clone(schema), builders.into_iter().map(ArrowColumn::finish).collect()).map_err(|e| e.to_string())
+}
+
+// every chunk is a record batch of up to ARROW_BATCH_SIZE rows, the first one carries the schema too
+fn arrow_body<R>(columns: Arc<[Column]>, keys: Vec<String>, rows: R, options: Arc<FormatOptions>) -> Result<Body, QueryError>
+where
+    R: Stream<Item = mysql_async::Result<Row>> + Send + Unpin + 'static,
+{
+    let schema: SchemaRef = Arc::new(Schema::new(columns.iter().zip(keys).map(|(c, key)| Field::new(key, arrow_type(c), true)).collect::<Vec<_>>()));
+    let writer = StreamWriter::try_new(Vec::new(), &schema).map_err(|e| QueryError::result(e.to_string()))?;
+
+    let chunks = stream::unfold((rows.chunks(ARROW_BATCH_SIZE), Some(writer)), move |(mut chunks, writer)| {
+        let (schema, columns) = (Arc::clone(&schema), Arc::clone(&columns));
+        let options = Arc::clone(&options);
+        async move {
+            let mut writer = writer?;
+            let done = match chunks.next().await {
+                Some(rows) => match arrow_batch(&schema, &columns, rows, &options).and_then(|batch| writer.write(&batch).map_err(|e| e.to_string())) {
+                    Ok(()) => false,
+                    Err(e) => return Some((Err(e), (chunks, None))),
+                },
+                None => match writer.finish() {
+                    Ok(()) => true,
+                    Err(e) => return Some((Err(e.to_string()), (chunks, None))),
+                },
+            };
+            let bytes = std::mem::take(writer.get_mut());
+            Some((Ok(bytes), (chunks, if done { None } else { Some(writer) })))
+        }
+    });
+
+    Ok(Body::wrap_stream(chunks))
+}
+
+// the timeout covers the whole response, past it the statement is killed and the error aborts the body
+fn rows_until<R>(rows: R, deadline: Option<(Instant, Duration)>, id: u32) -> stream::BoxStream<'static, mysql_async::Result<Row>>
+where
+    R: Stream<Item = mysql_async::Result<Row>> + Send + Unpin + 'static,
+{
+    let (deadline, timeout) = match deadline {
+        Some(deadline) => deadline,
+        None => return rows.boxed(),
+    };
+    stream::unfold(Some(rows), move |rows| async move {
+        let mut rows = rows?;
+        // a slow client can leave the deadline behind while rows are still buffered
+        if Instant::now() < deadline {
+            if let Ok(row) = tokio::time::timeout_at(deadline.into(), rows.next()).await {
+                return row.map(|row| (row, Some(rows)));
+            }
+        }
+        // the rows hold on to the connection until the kill is done
+        kill_query(id).await;
+        drop(rows);
+        Some((Err(mysql_async::Error::Other(QueryError::timeout(timeout).message.into())), None))
+    }).boxed()
+}
+
+// rows are pulled from the connection only when hyper asks for the next chunk, so a slow client slows down the reads
+async fn stream_rows<P: Protocol + Unpin>(result: mysql_async::QueryResult<'static, 'static, P>, options: FormatOptions, format: StreamFormat, id: u32, deadline: Option<(Instant, Duration)>) -> Result<Body, QueryError> {
+    let columns = result.columns().unwrap_or_else(|| Arc::new([]));
+    let keys = column_keys(&columns, options.duplicates)?;
+    if let StreamFormat::Arrow = format {
+        let options = Arc::new(options);
+        return match result.stream_and_drop::<Row>().await.map_err(|e| error("MySQL query error", e))? {
+            Some(rows) => arrow_body(columns, keys, rows_until(rows, deadline, id), options),
+            None => arrow_body(columns, keys, stream::empty(), options),
+        };
+    }
+
+    let header = match (format, options.format) {
+        (StreamFormat::Ndjson, ResultFormat::Objects) if !options.meta => None,
+        (StreamFormat::Ndjson, ResultFormat::Columnar) => return Err(QueryError::result("Columnar format can't be streamed".to_owned())),
+        (StreamFormat::Ndjson, _) => Some(ndjson_line(&json!({ "columns": ResultColumns { columns: &columns, keys: &keys, options: &options } })).map_err(|e| QueryError::result(e.to_string()))?),
+        _ => Some(delimited_line(keys.iter().map(|k| Some(Cow::Borrowed(k.as_str()))), format)),
+    };
+
+    let rows = match result.stream_and_drop::<Row>().await.map_err(|e| error("MySQL query error", e))? {
+        Some(rows) => rows,
+        None => return Ok(Body::from(header.unwrap_or_default())),
+    };
+    let lines = rows_until(rows, deadline, id).map(move |row| {
+        let row = row.map_err(|e| error("MySQL stream error", e).message)?;
+        let line = match (format, options.format) {
+            (StreamFormat::Ndjson, ResultFormat::Compact) => ndjson_line(&RowValues { row: &row, options: &options }),
+            (StreamFormat::Ndjson, _) => ndjson_line(&QueryRow { row: &row, keys: &keys, options: &options }),
+            _ => delimited_row(&row, &options, format),
+        };
+        line.map_err(|e| e.to_string())
+    });
+
+    Ok(Body::wrap_stream(stream::iter(header.map(Ok)).chain(lines)))
+}
+
+struct ExecOptions {
+    timeout: Option<Duration>,
+    database: Option<String>,
+    tx: Option<String>,
+    // None when none of the transaction options were given
+    tx_opts: Option<TxOpts>,
+    on_error: Option<OnError>,
+    retries: u32,
+}
+
+impl ExecOptions {
+    // transaction options only mean something where a transaction begins, savepoints where one is open
+    fn single_statement(&self) -> Result<(), QueryError> {
+        match (&self.tx_opts, &self.on_error, &self.tx) {
+            (Some(_), _, Some(_)) => Err(QueryError::bind("Transaction options are set when the transaction begins".to_owned())),
+            (Some(_), _, None) => Err(QueryError::bind("Transaction options only apply to batches and to POST /tx".to_owned())),
+            (None, Some(_), None) => Err(QueryError::bind("`on_error` only applies inside a batch or a transaction".to_owned())),
+            _ => Ok(()),
+        }
+    }
+}
+
+async fn get_pool(database: Option<&str>) -> Result<Pool, QueryError> {
+    let database = match database {
+        Some(database) => database,
+        None => return Ok(POOL.clone()),
+    };
+    let pool = DATABASE_POOLS.lock().unwrap().get(database).cloned();
+    if let Some(pool) = pool {
+        return Ok(pool);
+    }
+    if DATABASE_POOLS.lock().unwrap().len() >= *DATABASE_POOLS_MAX {
+        return Err(QueryError::database_limit());
+    }
+
+    // the pool is only kept once a connection succeeds, so unknown databases don't pile up
+    let pool = Pool::new(OptsBuilder::from_opts(OPTS.clone()).db_name(Some(database)).pool_opts(DATABASE_POOL_OPTS.clone()));
+    if let Err(e) = pool.get_conn().await {
+        discard_pool(pool);
+        return Err(error("MySQL connection error", e).connection());
+    }
+    let kept = {
+        let mut pools = DATABASE_POOLS.lock().unwrap();
+        match pools.get(database) {
+            // a concurrent request got there first
+            Some(existing) => Ok(existing.clone()),
+            None if pools.len() >= *DATABASE_POOLS_MAX => Err(QueryError::database_limit()),
+            None => {
+                pools.insert(database.to_owned(), pool.clone());
+                return Ok(pool);
+            },
+        }
+    };
+    discard_pool(pool);
+    kept
+}
+
+fn discard_pool(pool: Pool) {
+    tokio::spawn(async move {
+        if let Err(e) = pool.disconnect().await {
+            error!("MySQL disconnect error: {}", e);
+        }
+    });
+}
+
+async fn get_conn(database: Option<&str>) -> Result<Conn, QueryError> {
+    get_pool(database).await?.get_conn().await.map_err(|e| error("MySQL connection error", e).connection())
+}
+
+// an interactive transaction, its connection stays checked out of the pool until it's committed or rolled back
+struct Session {
+    // None once the transaction is finished
+    tx: tokio::sync::Mutex<Option<Transaction<'static>>>,
+    last_used: Mutex<Instant>,
+    _slots: Vec<OwnedSemaphorePermit>,
+}
+
+impl Session {
+    fn touch(&self) {
+        *self.last_used.lock().unwrap() = Instant::now();
+    }
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| panic!("Invalid env var {}", name)),
+        Err(_) => default,
+    }
+}
+
+static SESSIONS: Lazy<Mutex<HashMap<String, Arc<Session>>>> = Lazy::new(Default::default);
+
+// every open transaction holds a pooled connection, so they're capped below the pool size
+static SESSION_SLOTS: Lazy<Arc<Semaphore>> = Lazy::new(|| {
+    let max = env_or("TX_MAX_OPEN", 16);
+    let pool_max = OPTS.pool_opts().constraints().max();
+    assert!(max < pool_max, "Invalid env var TX_MAX_OPEN, it must be lower than the pool size {}", pool_max);
+    Arc::new(Semaphore::new(max))
+});
+
+// database pools are smaller, so each gets its own cap too, created along with the pool
+static DATABASE_SESSION_SLOTS: Lazy<Mutex<HashMap<String, Arc<Semaphore>>>> = Lazy::new(Default::default);
+
+static SESSION_IDLE_TIMEOUT: Lazy<Duration> = Lazy::new(|| Duration::from_secs(env_or("TX_IDLE_TIMEOUT", 60)));
+
+fn session(id: &str) -> Result<Arc<Session>, QueryError> {
+    SESSIONS.lock().unwrap().get(id).cloned().ok_or_else(|| QueryError::session(id))
+}
+
+async fn _begin(params: CallParams) -> Result<String, QueryError> {
+    let exec = params.exec_options()?;
+    if exec.tx.is_some() {
+        return Err(QueryError::bind("Transactions can't be nested".to_owned()));
+    }
+    let mut slots = vec![Arc::clone(&SESSION_SLOTS).try_acquire_owned().map_err(|_| QueryError::session_limit())?];
+    let pool = get_pool(exec.database.as_deref()).await?;
+    if let Some(database) = exec.database.as_deref() {
+        let database_slots = Arc::clone(DATABASE_SESSION_SLOTS.lock().unwrap().entry(database.to_owned())
+            .or_insert_with(|| Arc::new(Semaphore::new(*DATABASE_POOL_SIZE - 1))));
+        slots.push(database_slots.try_acquire_owned().map_err(|_| QueryError::session_limit())?);
+    }
+    let tx = pool.start_transaction(exec.tx_opts.unwrap_or_default()).await.map_err(|e| error("MySQL transaction error", e).connection())?;
+    let id = format!("{:032x}", rand::random::<u128>());
+    let session = Session { tx: tokio::sync::Mutex::new(Some(tx)), last_used: Mutex::new(Instant::now()), _slots: slots };
+    SESSIONS.lock().unwrap().insert(id.clone(), Arc::new(session));
+    Ok(id)
+}
+
+async fn _finish(id: &str, commit: bool) -> Result<(), QueryError> {
+    let session = SESSIONS.lock().unwrap().remove(id).ok_or_else(|| QueryError::session(id))?;
+    // waits for a statement still running on the transaction
+    let tx = session.tx.lock().await.take().ok_or_else(|| QueryError::session(id))?;
+    if commit {
+        tx.commit().await.map_err(|e| error("MySQL commit error", e))
+    }
+    else {
+        tx.rollback().await.map_err(|e| error("MySQL rollback error", e))
+    }
+}
+
+async fn reap_sessions() {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let mut idle = Vec::new();
+        // a locked session is running a statement, so it isn't idle, an unlocked one is taken under the same lock
+        // so no statement can slip in before the rollback
+        SESSIONS.lock().unwrap().retain(|id, session| {
+            if session.last_used.lock().unwrap().elapsed() <= *SESSION_IDLE_TIMEOUT {
+                return true;
+            }
+            match session.tx.try_lock() {
+                Ok(mut tx) => {
+                    // the session keeps its slots until the rollback is done
+                    idle.extend(tx.take().map(|tx| (id.clone(), Arc::clone(session), tx)));
+                    false
+                },
+                Err(_) => true,
+            }
+        });
+        for (id, _session, tx) in idle {
+            warn!("Rolling back idle transaction {}", id);
+            if let Err(e) = tx.rollback().await {
+                error!("MySQL rollback error: {}", e);
+            }
+        }
+    }
+}
+
+async fn kill_query(id: u32) {
+    let killed = match POOL.get_conn().await {
+        Ok(mut conn) => conn.query_drop(format!("KILL QUERY {}", id)).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = killed {
+        error!("MySQL kill error: {}", e);
+    }
+}
+
+// dropping the future alone leaves the statement running on the server, so it gets killed too
+async fn timed<T, F: Future<Output = Result<T, QueryError>>>(timeout: Option<Duration>, id: u32, f: F) -> Result<T, QueryError> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return f.await,
+    };
+    let mut f = Box::pin(f);
+    match tokio::time::timeout(timeout, &mut f).await {
+        Ok(result) => result,
+        // `f` holds on to the connection until the kill is done, so its id can't have gone to another statement meanwhile
+        Err(_) => {
+            kill_query(id).await;
+            drop(f);
+            Err(QueryError::timeout(timeout))
+        },
+    }
+}
+
+async fn _stream(query: Query, options: FormatOptions, format: StreamFormat, exec: ExecOptions) -> Result<Body, QueryError> {
+    exec.single_statement()?;
+    // only the first result set would make it into the stream
+    if options.multi {
+        return Err(QueryError::result("Multiple result sets can't be streamed".to_owned()));
+    }
+    if exec.retries > 0 {
+        return Err(QueryError::result("Streamed results can't be retried".to_owned()));
+    }
+    if exec.tx.is_some() {
+        return Err(QueryError::result("Results can't be streamed inside a transaction".to_owned()));
+    }
+    let (q, params) = query.into_parts()?;
+    let conn = get_conn(exec.database.as_deref()).await?;
+    let id = conn.id();
+    let deadline = exec.timeout.map(|timeout| (Instant::now() + timeout, timeout));
+    timed(exec.timeout, id, async move {
+        match params {
+            None => stream_rows(q.run(conn).await.map_err(|e| error("MySQL query error", e))?, options, format, id, deadline).await,
+            Some(p) => stream_rows(q.with(p).run(conn).await.map_err(|e| error("MySQL query error", e))?, options, format, id, deadline).await,
+        }
+    }).await
+}
+
+async fn _query(query: Query, summary: bool, options: FormatOptions, exec: ExecOptions) -> Result<QueryResult, QueryError> {
+    exec.single_statement()?;
+    let (q, params) = query.into_parts()?;
+    if let Some(id) = exec.tx {
+        if exec.database.is_some() {
+            return Err(QueryError::bind("`database` is fixed when the transaction begins".to_owned()));
+        }
+        // a deadlock rolls back the whole transaction, which only the client can replay
+        if exec.retries > 0 {
+            return Err(QueryError::bind("`retries` doesn't apply inside a transaction".to_owned()));
+        }
+        let session = session(&id)?;
+        let mut guard = session.tx.lock().await;
+        let tx = guard.as_mut().ok_or_else(|| QueryError::session(&id))?;
+        let conn_id = tx.id();
+        let (result, ended) = match exec.on_error.unwrap_or_default() {
+            OnError::Abort => {
+                let result = timed(exec.timeout, conn_id, execute(tx, q, params, summary, Arc::new(options))).await;
+                let ended = matches!(&result, Err(e) if e.ends_transaction());
+                (result, ended)
+            },
+            // the statement's error is returned either way, but the transaction is left as it was before it
+            OnError::RollbackToSavepoint => match timed(exec.timeout, conn_id, execute_savepoint(tx, q, params, summary, Arc::new(options))).await {
+                Ok(result) => (result, false),
+                // a killed statement is undone on its own, any other outer error leaves the transaction unusable
+                Err(e) => {
+                    let ended = e.stage != Stage::Timeout;
+                    (Err(e), ended)
+                },
+            },
+        };
+        // otherwise later statements would run in autocommit while the client thinks they're in the transaction
+        if ended {
+            SESSIONS.lock().unwrap().remove(&id);
+            // dropped, it's rolled back when the connection goes back to the pool
+            guard.take();
+            return result.map_err(|e| e.transaction_ended(&id));
+        }
+        session.touch();
+        return result;
+    }
+
+    let retries = if is_read_only(&q) { exec.retries } else { 0 };
+    let mut conn = get_conn(exec.database.as_deref()).await?;
+    let id = conn.id();
+    timed(exec.timeout, id, execute_retrying(&mut conn, q, params, summary, Arc::new(options), retries)).await
+}
+
+const MAX_RETRIES: u32 = 10;
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(50);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(2);
+
+// exponential, with the upper half jittered so retrying clients don't collide again
+async fn backoff(attempt: u32) {
+    let delay = RETRY_BASE_DELAY.saturating_mul(1 << attempt.min(16)).min(RETRY_MAX_DELAY) / 2;
+    tokio::time::sleep(delay + delay.mul_f64(rand::random::<f64>())).await;
+}
+
+// a statement on its own is only re-run when it can't have changed anything, a leading comment or a second statement rules it out
+fn is_read_only(sql: &str) -> bool {
+    let sql = sql.trim().trim_end_matches(';');
+    let keyword = sql.split(|c: char| !c.is_ascii_alphabetic()).next().unwrap_or_default();
+    ["select", "show", "describe", "desc", "explain"].iter().any(|k| keyword.eq_ignore_ascii_case(k)) && !sql.contains(';')
+}
+
+async fn execute_retrying(conn: &mut Conn, q: String, params: Option<Params>, summary: bool, options: Arc<FormatOptions>, retries: u32) -> Result<QueryResult, QueryError> {
+    let mut attempt = 0;
+    loop {
+        match execute(conn, q.clone(), params.clone(), summary, Arc::clone(&options)).await {
+            Err(e) if attempt < retries && e.is_retryable() => {
+                warn!("Retrying statement after error {:?}, attempt {} of {}", e.code, attempt + 1, retries);
+                backoff(attempt).await;
+                attempt += 1;
+            },
+            result => return result,
+        }
+    }
+}
+
+async fn execute<C: Queryable>(conn: &mut C, q: String, params: Option<Params>, summary: bool, options: Arc<FormatOptions>) -> Result<QueryResult, QueryError> {
+    if options.multi {
+        let sets = match params {
+            None => collect_sets(conn.query_iter(q).await.map_err(|e| error("MySQL query error", e))?).await,
+            Some(p) => collect_sets(conn.exec_iter(q, p).await.map_err(|e| error("MySQL query error", e))?).await,
+        };
+        return sets.map_err(|e| error("MySQL query error", e))?.into_iter()
+            .map(|set| match set {
+                Collected::Rows(columns, rows) => wrap_rows(columns, rows, Arc::clone(&options)),
+                Collected::Write(summary) => Ok(QueryResult::Write(summary)),
+            })
+            .collect::<Result<_, _>>()
+            .map(QueryResult::Sets);
+    }
+
+    let collected = match params {
+        None => collect_rows(conn.query_iter(q).await.map_err(|e| error("MySQL query error", e))?, summary).await,
+        Some(p) => collect_rows(conn.exec_iter(q, p).await.map_err(|e| error("MySQL query error", e))?, summary).await,
+    };
+    match collected.map_err(|e| error("MySQL query error", e))? {
+        Collected::Rows(columns, rows) => wrap_rows(columns, rows, options),
+        Collected::Write(mut summary) => {
+            if options.show_warnings && summary.warnings > 0 {
+                summary.warning_details = Some(show_warnings(conn).await.map_err(|e| error("MySQL warnings error", e))?);
+            }
+            Ok(QueryResult::Write(summary))
+        },
+    }
+}
+
+struct Statement {
+    sql: String,
+    params: Option<Params>,
+    summary: bool,
+    options: Arc<FormatOptions>,
+    on_error: OnError,
+}
+
+const SAVEPOINT: &str = "mysql_proxy_statement";
+
+// the inner error is a statement rolled back to its savepoint, the outer one a transaction that can't go on
+async fn execute_savepoint<C: Queryable>(conn: &mut C, q: String, params: Option<Params>, summary: bool, options: Arc<FormatOptions>) -> Result<Result<QueryResult, QueryError>, QueryError> {
+    conn.query_drop(format!("SAVEPOINT {}", SAVEPOINT)).await.map_err(|e| error("MySQL savepoint error", e))?;
+    match execute(conn, q, params, summary, options).await {
+        Ok(result) => {
+            conn.query_drop(format!("RELEASE SAVEPOINT {}", SAVEPOINT)).await.map_err(|e| error("MySQL savepoint error", e))?;
+            Ok(Ok(result))
+        },
+        // a deadlock rolls back the whole transaction, the savepoint included
+        Err(e) => match conn.query_drop(format!("ROLLBACK TO SAVEPOINT {}", SAVEPOINT)).await {
+            Ok(()) => Ok(Err(e)),
+            Err(rollback) => {
+                error!("MySQL savepoint error: {}", rollback);
+                Err(e)
+            },
+        },
+    }
+}
+
+// a failed statement rolls back the whole batch, an unfinished transaction is rolled back when the connection goes back to the pool
+async fn run_batch(conn: &mut Conn, statements: &[Statement], tx_opts: TxOpts) -> Result<Vec<QueryResult>, QueryError> {
+    let mut tx = conn.start_transaction(tx_opts).await.map_err(|e| error("MySQL transaction error", e))?;
+    let mut results = Vec::with_capacity(statements.len());
+    for (index, s) in statements.iter().enumerate() {
+        let result = match s.on_error {
+            OnError::Abort => execute(&mut tx, s.sql.clone(), s.params.clone(), s.summary, Arc::clone(&s.options)).await,
+            OnError::RollbackToSavepoint => execute_savepoint(&mut tx, s.sql.clone(), s.params.clone(), s.summary, Arc::clone(&s.options)).await
+                .map(|r| r.unwrap_or_else(|error| QueryResult::Skipped(Skipped { skipped: true, error }))),
+        };
+        match result {
+            Ok(result) => results.push(result),
+            Err(e) => {
+                if let Err(e) = tx.rollback().await {
+                    error!("MySQL rollback error: {}", e);
+                }
+                return Err(e.at(index));
+            },
+        }
+    }
+    tx.commit().await.map_err(|e| error("MySQL commit error", e))?;
+    Ok(results)
+}
+
+// the whole transaction was rolled back, so replaying all of it is safe
+async fn run_batch_retrying(conn: &mut Conn, statements: Vec<Statement>, tx_opts: TxOpts, retries: u32) -> Result<Vec<QueryResult>, QueryError> {
+    let mut attempt = 0;
+    loop {
+        match run_batch(conn, &statements, tx_opts.clone()).await {
+            Err(e) if attempt < retries && e.is_retryable() => {
+                warn!("Retrying batch after error {:?} at statement {:?}, attempt {} of {}", e.code, e.index, attempt + 1, retries);
+                backoff(attempt).await;
+                attempt += 1;
+            },
+            result => return result,
+        }
+    }
+}
+
+async fn _batch(queries: Vec<Query>, params: CallParams) -> Result<Vec<QueryResult>, QueryError> {
+    let exec = params.exec_options()?;
+    if exec.tx.is_some() {
+        return Err(QueryError::bind("A batch runs in its own transaction and can't take `tx`".to_owned()));
+    }
+    // everything is bound before the transaction starts, so a bad param doesn't cost a round trip
+    let statements = queries.into_iter().enumerate()
+        .map(|(index, mut query)| {
+            let options = match query.take_options() {
+                Some(options) if options.timeout_ms.is_some() || options.database.is_some() || options.tx.is_some() || options.retries.is_some() || options.has_tx_opts() => {
+                    return Err(QueryError::bind("`timeout_ms`, `database`, `tx`, `retries` and transaction options apply to the whole batch".to_owned()).at(index));
+                },
+                Some(options) => options.or(params.clone()),
+                None => params.clone(),
+            };
+            if let Some(ResultFormat::Csv | ResultFormat::Tsv | ResultFormat::Arrow) = options.format {
+                return Err(QueryError::bind("`csv`, `tsv` and `arrow` results are only streamed, a batch can't return them".to_owned()).at(index));
+            }
+            let (sql, params) = query.into_parts().map_err(|e| e.at(index))?;
+            Ok(Statement { sql, params, summary: options._return == Some(true), options: Arc::new(options.format_options().map_err(|e| e.at(index))?), on_error: options.on_error.unwrap_or_default() })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut conn = get_conn(exec.database.as_deref()).await?;
+    let id = conn.id();
+    timed(exec.timeout, id, run_batch_retrying(&mut conn, statements, exec.tx_opts.unwrap_or_default(), exec.retries)).await
+}
+
+#[derive(Clone, Default, Deserialize)]
+struct CallParams {
+    #[serde(rename = "return")]
+    _return: Option<bool>,
+    timeout_ms: Option<u64>,
+    database: Option<String>,
+    tx: Option<String>,
+    isolation: Option<Isolation>,
+    read_only: Option<bool>,
+    consistent_snapshot: Option<bool>,
+    on_error: Option<OnError>,
+    retries: Option<u32>,
+    dates: Option<DateFormat>,
+    tz: Option<String>,
+    binary: Option<BinaryFormat>,
+    numbers: Option<NumberFormat>,
+    decimals: Option<DecimalFormat>,
+    meta: Option<bool>,
+    duplicates: Option<DuplicatePolicy>,
+    format: Option<ResultFormat>,
+    warnings: Option<bool>,
+    multi: Option<bool>,
+}
+
+impl CallParams {
+    // options from the request body win over the query string
+    fn or(self, fallback: CallParams) -> CallParams {
+        CallParams {
+            _return: self._return.or(fallback._return),
+            timeout_ms: self.timeout_ms.or(fallback.timeout_ms),
+            database: self.database.or(fallback.database),
+            tx: self.tx.or(fallback.tx),
+            isolation: self.isolation.or(fallback.isolation),
+            read_only: self.read_only.or(fallback.read_only),
+            consistent_snapshot: self.consistent_snapshot.or(fallback.consistent_snapshot),
+            on_error: self.on_error.or(fallback.on_error),
+            retries: self.retries.or(fallback.retries),
+            dates: self.dates.or(fallback.dates),
+            tz: self.tz.or(fallback.tz),
+            binary: self.binary.or(fallback.binary),
+            numbers: self.numbers.or(fallback.numbers),
+            decimals: self.decimals.or(fallback.decimals),
+            meta: self.meta.or(fallback.meta),
+            duplicates: self.duplicates.or(fallback.duplicates),
+            format: self.format.or(fallback.format),
+            warnings: self.warnings.or(fallback.warnings),
+            multi: self.multi.or(fallback.multi),
+        }
+    }
+
+    fn has_tx_opts(&self) -> bool {
+        self.isolation.is_some() || self.read_only.is_some() || self.consistent_snapshot.is_some()
+    }
+
+    fn tx_opts(&self) -> Result<TxOpts, QueryError> {
+        // InnoDB silently ignores a consistent snapshot at any other isolation level
+        if self.consistent_snapshot == Some(true) && !matches!(self.isolation, None | Some(Isolation::RepeatableRead)) {
+            return Err(QueryError::bind("`consistent_snapshot` needs the `repeatable_read` isolation level".to_owned()));
+        }
+        let mut opts = TxOpts::new();
+        opts.with_isolation_level(self.isolation.map(IsolationLevel::from))
+            .with_readonly(self.read_only)
+            .with_consistent_snapshot(self.consistent_snapshot == Some(true));
+        Ok(opts)
+    }
+
+    fn exec_options(&self) -> Result<ExecOptions, QueryError> {
+        Ok(ExecOptions {
+            timeout: self.timeout_ms.map(Duration::from_millis),
+            database: self.database.clone(),
+            tx: self.tx.clone(),
+            tx_opts: if self.has_tx_opts() { Some(self.tx_opts()?) } else { None },
+            on_error: self.on_error,
+            retries: match self.retries {
+                Some(retries) if retries > MAX_RETRIES => return Err(QueryError::bind(format!("`retries` can't be over {}", MAX_RETRIES))),
+                retries => retries.unwrap_or_default(),
+            },
+        })
+    }
+
+    fn format_options(&self) -> Result<FormatOptions, QueryError> {
+        if let Some(tz) = self.tz.as_deref().filter(|tz| !is_utc_offset(tz)) {
+            return Err(QueryError::bind(format!("Invalid `tz` `{}`, expected `Z` or `±HH:MM`", tz)));
+        }
+        Ok(FormatOptions {
+            dates: self.dates.unwrap_or_default(),
+            tz: self.tz.clone(),
+            binary: self.binary.unwrap_or_default(),
+            numbers: self.numbers.unwrap_or_default(),
+            decimals: self.decimals.unwrap_or_default(),
+            meta: self.meta == Some(true),
+            duplicates: self.duplicates.unwrap_or_default(),
+            format: self.format.unwrap_or_default(),
+            show_warnings: self.warnings == Some(true),
+            multi: self.multi == Some(true),
+        })
+    }
+
+    // an explicit `format` wins over the Accept header, only NDJSON can still carry the JSON formats
+    fn stream_format(&self, accept: &Option<String>) -> Option<StreamFormat> {
+        match self.format {
+            Some(ResultFormat::Csv) => Some(StreamFormat::Csv),
+            Some(ResultFormat::Tsv) => Some(StreamFormat::Tsv),
+            Some(ResultFormat::Arrow) => Some(StreamFormat::Arrow),
+            Some(_) if accepts(accept, "application/x-ndjson") => Some(StreamFormat::Ndjson),
+            Some(_) => None,
+            _ if accepts(accept, "text/csv") => Some(StreamFormat::Csv),
+            _ if accepts(accept, "text/tab-separated-values") => Some(StreamFormat::Tsv),
+            _ if accepts(accept, "application/vnd.apache.arrow.stream") => Some(StreamFormat::Arrow),
+            _ if accepts(accept, "application/x-ndjson") => Some(StreamFormat::Ndjson),
+            _ => None,
+        }
+    }
+}
+
+const OPTIONS: &[&str] = &["return", "timeout_ms", "database", "tx", "isolation", "read_only", "consistent_snapshot", "on_error", "retries", "dates", "tz", "binary", "numbers", "decimals", "meta", "duplicates", "format", "warnings", "multi"];
+
+// the `options` of an object body, which unlike the query string rejects unknown names
+struct BodyOptions(CallParams);
+
+impl<'de> Deserialize<'de> for BodyOptions {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct OptionsVisitor;
+
+        impl<'de> Visitor<'de> for OptionsVisitor {
+            type Value = BodyOptions;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an object of options")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<BodyOptions, A::Error> {
+                let mut o = CallParams::default();
+                while let Some(key) = map.next_key::<String>()? {
+                    let path = format!("options.{}", key);
+                    match key.as_str() {
+                        "return" => o._return = field(&mut map, &path)?,
+                        "timeout_ms" => o.timeout_ms = field(&mut map, &path)?,
+                        "database" => o.database = field(&mut map, &path)?,
+                        "tx" => o.tx = field(&mut map, &path)?,
+                        "isolation" => o.isolation = field(&mut map, &path)?,
+                        "read_only" => o.read_only = field(&mut map, &path)?,
+                        "consistent_snapshot" => o.consistent_snapshot = field(&mut map, &path)?,
+                        "on_error" => o.on_error = field(&mut map, &path)?,
+                        "retries" => o.retries = field(&mut map, &path)?,
+                        "dates" => o.dates = field(&mut map, &path)?,
+                        "tz" => o.tz = field(&mut map, &path)?,
+                        "binary" => o.binary = field(&mut map, &path)?,
+                        "numbers" => o.numbers = field(&mut map, &path)?,
+                        "decimals" => o.decimals = field(&mut map, &path)?,
+                        "meta" => o.meta = field(&mut map, &path)?,
+                        "duplicates" => o.duplicates = field(&mut map, &path)?,
+                        "format" => o.format = field(&mut map, &path)?,
+                        "warnings" => o.warnings = field(&mut map, &path)?,
+                        "multi" => o.multi = field(&mut map, &path)?,
+                        _ => return Err(de::Error::custom(format_args!("`{}`: unknown option, expected one of `{}`", path, OPTIONS.join("`, `")))),
+                    }
+                }
+                Ok(BodyOptions(o))
+            }
+        }
+
+        deserializer.deserialize_map(OptionsVisitor)
+    }
+}
+
+// the ISO date suffix: `Z` or `±HH:MM`
+fn is_utc_offset(tz: &str) -> bool {
+    if tz == "Z" {
+        return true;
+    }
+    match tz.as_bytes() {
+        [b'+' | b'-', h1, h2, b':', m1, m2] if [h1, h2, m1, m2].iter().all(|b| b.is_ascii_digit()) => &tz[1..3] <= "23" && &tz[4..6] <= "59",
+        _ => false,
+    }
+}
+
+// one option at a time, so an error names the option the way body options do
+fn query_params(query: &str) -> Result<CallParams, QueryError> {
+    query.split('&').filter(|pair| !pair.is_empty()).try_fold(CallParams::default(), |params, pair| {
+        let option = serde_urlencoded::from_str::<CallParams>(pair).map_err(|e| {
+            let name = serde_urlencoded::from_str::<Vec<(String, String)>>(pair).ok().and_then(|pairs| pairs.into_iter().next()).map(|(name, _)| name).unwrap_or_default();
+            QueryError::bind(format!("Invalid query string: `{}`: {}", name, e))
+        })?;
+        Ok(option.or(params))
+    })
+}
+
+// warp rejects a missing query string, and a bad one with a plain text reply
+fn query_string() -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
+    warp::filters::query::raw().or(warp::any().map(String::new)).unify()
+}
+
+fn accepts(accept: &Option<String>, mime: &str) -> bool {
+    accept.as_deref().map(|a| a.split(',').any(|m| m.split(';').next().map(str::trim) == Some(mime))).unwrap_or(false)
+}
+
+fn error_response(e: QueryError, encoding: Encoding) -> warp::reply::Response {
+    encoding.reply(&json!({ "error": e }), STATUS_MAP.status(&e))
+}
+
+fn decode_body<T: DeserializeOwned>(body: &[u8], content_type: &Option<String>) -> Result<T, QueryError> {
+    match Encoding::from_content_type(content_type) {
+        Some(decoder) => decoder.decode(body).map_err(|e| QueryError::bind(format!("Invalid request body: {}", e))),
+        None => Err(QueryError::content_type(content_type.as_deref().unwrap_or_default())),
+    }
+}
+
+async fn query(body: Bytes, query: String, content_type: Option<String>, accept: Option<String>) -> Result<impl warp::Reply, Infallible> {
+    let encoding = Encoding::from_accept(&accept);
+    let (mut q, params) = match decode_body::<Query>(&body, &content_type).and_then(|q| Ok((q, query_params(&query)?))) {
+        Ok(decoded) => decoded,
+        Err(e) => return Ok(error_response(e, encoding)),
+    };
+    let params = match q.take_options() {
+        Some(options) => options.or(params),
+        None => params,
+    };
+
+    let (exec, options) = match params.exec_options().and_then(|exec| Ok((exec, params.format_options()?))) {
+        Ok(options) => options,
+        Err(e) => return Ok(error_response(e, encoding)),
+    };
+
+    if let Some(format) = params.stream_format(&accept).filter(|_| params._return != Some(true)) {
+        return Ok(match _stream(q, options, format, exec).await {
+            Ok(body) => warp::reply::with_header(warp::reply::Response::new(body), "content-type", format.content_type()).into_response(),
+            Err(e) => error_response(e, encoding),
+        });
+    }
+
+    Ok(match _query(q, params._return == Some(true), options, exec).await {
+        Ok(v) => encoding.reply(&v, StatusCode::OK),
+        Err(e) => error_response(e, encoding),
+    })
+}
+
+async fn batch(body: Bytes, query: String, content_type: Option<String>, accept: Option<String>) -> Result<impl warp::Reply, Infallible> {
+    let encoding = Encoding::from_accept(&accept);
+    let (Batch(queries), params) = match decode_body(&body, &content_type).and_then(|batch| Ok((batch, query_params(&query)?))) {
+        Ok(decoded) => decoded,
+        Err(e) => return Ok(error_response(e, encoding)),
+    };
+
+    Ok(match _batch(queries, params).await {
+        Ok(v) => encoding.reply(&v, StatusCode::OK),
+        Err(e) => error_response(e, encoding),
+    })
+}
+
+async fn begin(query: String, accept: Option<String>) -> Result<impl warp::Reply, Infallible> {
+    let encoding = Encoding::from_accept(&accept);
+    let params = match query_params(&query) {
+        Ok(params) => params,
+        Err(e) => return Ok(error_response(e, encoding)),
+    };
+    Ok(match _begin(params).await {
+        Ok(id) => encoding.reply(&json!({ "id": id }), StatusCode::CREATED),
+        Err(e) => error_response(e, encoding),
+    })
+}
+
+async fn finish(id: String, commit: bool, accept: Option<String>) -> Result<impl warp::Reply, Infallible> {
+    Ok(match _finish(&id, commit).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(e, Encoding::from_accept(&accept)),
+    })
+}
+
+#[tokio::main]
+pub async fn main() {
+    env_logger::init();
+    Lazy::force(&STATUS_MAP);
+    Lazy::force(&DATABASE_POOLS_MAX);
+    Lazy::force(&DATABASE_POOL_OPTS);
+    Lazy::force(&SESSION_SLOTS);
+    Lazy::force(&SESSION_IDLE_TIMEOUT);
+    tokio::spawn(reap_sessions());
+
+    let batch = warp::post()
+        .and(warp::path("batch"))
+        .and(warp::path::end())
+        .and(warp::body::bytes())
+        .and(query_string())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::header::optional::<String>("accept"))
+        .and_then(batch);
+
+    let begin = warp::post()
+        .and(warp::path("tx"))
+        .and(warp::path::end())
+        .and(query_string())
+        .and(warp::header::optional::<String>("accept"))
+        .and_then(begin);
+
+    let commit = warp::post()
+        .and(warp::path!("tx" / String / "commit"))
+        .and(warp::header::optional::<String>("accept"))
+        .and_then(|id, accept| finish(id, true, accept));
+
+    let rollback = warp::post()
+        .and(warp::path!("tx" / String / "rollback"))
+        .and(warp::header::optional::<String>("accept"))
+        .and_then(|id, accept| finish(id, false, accept));
+
+    let promote = warp::post()
+        .and(warp::body::bytes())
+        .and(query_string())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::header::optional::<String>("accept"))
+        .and_then(query);
+
+    warp::serve(batch.or(begin).or(commit).or(rollback).or(promote)).run(([0, 0, 0, 0], 3030)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time_column(decimals: u8) -> Column {
+        Column::new(ColumnType::MYSQL_TYPE_TIME).with_decimals(decimals)
+    }
+
+    #[test]
+    fn format_time_negative_multi_day_micros() {
+        assert_eq!(format_time(true, 1, 2, 3, 4, 5, &time_column(31)), "-26:03:04.000005");
+        assert_eq!(format_time(false, 34, 22, 59, 59, 0, &time_column(0)), "838:59:59");
+    }
+
+    #[test]
+    fn format_time_follows_column_precision() {
+        assert_eq!(format_time(false, 0, 0, 0, 1, 500_000, &time_column(3)), "00:00:01.500");
+        assert_eq!(format_time(false, 0, 0, 0, 1, 0, &time_column(3)), "00:00:01.000");
+        assert_eq!(format_time(false, 0, 0, 0, 1, 0, &time_column(31)), "00:00:01");
+    }
+
+    #[test]
+    fn tz_must_be_an_offset() {
+        for tz in ["Z", "+00:00", "-05:30", "+14:00"] {
+            assert!(is_utc_offset(tz), "{}", tz);
+        }
+        for tz in ["foo", "z", "+5:00", "+24:00", "+05:60", "UTC", "+0530", ""] {
+            assert!(!is_utc_offset(tz), "{}", tz);
+        }
+    }
+
+    #[test]
+    fn convert_value_numbers() {
+        assert_eq!(convert_value(json!(i64::MIN)).ok(), Some(mysql_async::Value::Int(i64::MIN)));
+        assert_eq!(convert_value(json!(u64::MAX)).ok(), Some(mysql_async::Value::UInt(u64::MAX)));
+        assert_eq!(convert_value(json!(1.5)).ok(), Some(mysql_async::Value::Double(1.5)));
+        assert_eq!(convert_value(json!(-1)).ok(), Some(mysql_async::Value::Int(-1)));
+        assert_eq!(convert_value(json!(i64::MAX)).ok(), Some(mysql_async::Value::Int(i64::MAX)));
+    }
+
+    #[test]
+    fn unknown_object_params_are_rejected() {
+        for param in [json!({"base64": 5}), json!({"hexx": "ab"}), json!({"tpye": "date", "value": "2021-03-04"}), json!({}), json!([1, 2])] {
+            assert!(convert_value(param.clone()).is_err(), "{}", param);
+        }
+        assert_eq!(convert_value(json!({"hex": "ab"})).ok(), Some(mysql_async::Value::Bytes(vec![0xab])));
+        assert_eq!(convert_value(Value::Null).ok(), Some(mysql_async::Value::NULL));
+    }
+
+    #[test]
+    fn delimited_nulls_differ_from_empty_strings() {
+        let fields = || vec![None, Some(Cow::Borrowed("")), Some(Cow::Borrowed("\\N")), Some(Cow::Borrowed("a,b"))];
+        assert_eq!(delimited_line(fields(), StreamFormat::Csv), b",\"\",\\N,\"a,b\"\r\n");
+        assert_eq!(delimited_line(fields(), StreamFormat::Tsv), b"\\N\t\t\\\\N\ta,b\n");
+    }
+
+    #[test]
+    fn arrow_time_is_a_duration() {
+        assert_eq!(arrow_type(&time_column(0)), DataType::Duration(TimeUnit::Microsecond));
+        let mut column = ArrowColumn::new(&arrow_type(&time_column(0))).ok().unwrap();
+        column.append(Some(&mysql_async::Value::Time(true, 34, 22, 59, 59, 0)), &time_column(0), &FormatOptions::default()).unwrap();
+        column.append(Some(&mysql_async::Value::Time(false, 1, 2, 3, 4, 5)), &time_column(0), &FormatOptions::default()).unwrap();
+        let array = column.finish();
+        let array = array.as_any().downcast_ref::<arrow::array::DurationMicrosecondArray>().unwrap();
+        assert_eq!(array.value(0), -(838 * 3600 + 59 * 60 + 59) * 1_000_000);
+        assert_eq!(array.value(1), (26 * 3600 + 3 * 60 + 4) * 1_000_000 + 5);
+    }
+
+    #[test]
+    fn unsupported_content_type_is_a_415() {
+        let e = decode_body::<Query>(b"select 1", &Some("text/plain".to_owned())).err().unwrap();
+        assert_eq!(StatusMap::default().status(&e), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert!(e.message.contains("application/json"));
+    }
+
+    #[tokio::test]
+    async fn multi_is_not_streamed() {
+        let options = FormatOptions { multi: true, ..FormatOptions::default() };
+        let exec = CallParams::default().exec_options().ok().unwrap();
+        let e = _stream(Query::Simple("select 1; select 2".to_owned()), options, StreamFormat::Ndjson, exec).await.err().unwrap();
+        assert_eq!(StatusMap::default().status(&e), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn typed_dates() {
+        let typed = |kind: &str, value: &str| convert_value(json!({"type": kind, "value": value})).ok();
+        assert_eq!(typed("datetime", "2021-03-04 05:06:07.5"), Some(mysql_async::Value::Date(2021, 3, 4, 5, 6, 7, 500_000)));
+        assert_eq!(typed("datetime", "2021-03-04T05:06:07"), Some(mysql_async::Value::Date(2021, 3, 4, 5, 6, 7, 0)));
+        assert_eq!(typed("datetime", "2021-03-04T05:06:07+02:00"), None);
+        assert_eq!(typed("datetime", "2021-03-04T05:06:07Z"), None);
+        assert_eq!(typed("date", "2021-03-04"), Some(mysql_async::Value::Date(2021, 3, 4, 0, 0, 0, 0)));
+        assert_eq!(typed("date", "2021-03-04 05:06:07"), None);
+    }
+
+    #[tokio::test]
+    async fn batches_refuse_streamed_formats() {
+        let queries = || vec![Query::Simple("select 1".to_owned()), Query::Simple("select 2".to_owned())];
+        let params = CallParams { format: Some(ResultFormat::Csv), ..CallParams::default() };
+        assert_eq!(_batch(queries(), params).await.err().and_then(|e| e.index), Some(0));
+
+        let body = r#"["select 1", {"sql": "select 2", "options": {"format": "arrow"}}]"#;
+        let Batch(queries) = Encoding::Json.decode(body.as_bytes()).ok().unwrap();
+        assert_eq!(_batch(queries, CallParams::default()).await.err().and_then(|e| e.index), Some(1));
+    }
+
+    #[test]
+    fn deadlocks_and_broken_connections_end_transactions() {
+        let server = |code| QueryError::from(mysql_async::Error::Server(mysql_async::ServerError { code, message: String::new(), state: "40001".to_owned() }));
+        assert!(server(1213).ends_transaction());
+        assert!(!server(1062).ends_transaction());
+        assert!(!server(1205).ends_transaction());
+        assert!(QueryError::from(mysql_async::Error::Io(std::io::Error::from(std::io::ErrorKind::BrokenPipe).into())).ends_transaction());
+        assert!(QueryError::from(mysql_async::Error::Driver(DriverError::ConnectionClosed)).ends_transaction());
+        assert!(!QueryError::from(mysql_async::Error::Driver(DriverError::MixedParams)).ends_transaction());
+        assert!(server(1213).transaction_ended("ab").message.ends_with("transaction `ab` was rolled back and is closed"));
+    }
+
+    #[test]
+    fn query_string_errors_name_the_option() {
+        let params = query_params("format=compact&timeout_ms=5&tz=%2B02%3A00&unknown=1").ok().unwrap();
+        assert!(matches!(params.format, Some(ResultFormat::Compact)));
+        assert_eq!(params.timeout_ms, Some(5));
+        assert_eq!(params.tz.as_deref(), Some("+02:00"));
+        for (query, name) in [("format=xml", "format"), ("meta=true&timeout_ms=abc", "timeout_ms"), ("isolation=foo", "isolation")] {
+            let e = query_params(query).err().unwrap();
+            assert_eq!(StatusMap::default().status(&e), StatusCode::BAD_REQUEST);
+            assert!(e.message.contains(&format!("`{}`", name)), "{}", e.message);
+        }
+    }
+
+    #[test]
+    fn only_plain_reads_are_retried() {
+        for sql in ["select 1", "SHOW TABLES", "  Select * from t;  ", "describe t", "explain select 1"] {
+            assert!(is_read_only(sql), "{}", sql);
+        }
+        for sql in ["/* x */ select 1", "-- x\nselect 1", "(select 1)", "WITH t AS (select 1) DELETE FROM u", "select 1; delete from t", "UPDATE t SET a = 1", "selectinto", ""] {
+            assert!(!is_read_only(sql), "{}", sql);
+        }
+    }
+
+    fn named(names: &[&str]) -> HashMap<String, Param> {
+        names.iter().map(|name| (name.to_string(), Param::Json(json!(1)))).collect()
+    }
+
+    #[test]
+    fn named_params_must_match_the_sql() {
+        let e = convert_named_params("select :b, :a, :c", named(&["a"])).err().unwrap();
+        assert_eq!(e.message, "Missing named parameters: b, c");
+        let e = convert_named_params("select :a", named(&["a", "d", "b"])).err().unwrap();
+        assert_eq!(e.message, "Unknown named parameters: b, d");
+        assert_eq!(StatusMap::default().status(&e), StatusCode::BAD_REQUEST);
+        let e = convert_named_params("select ?, :a", named(&["a"])).err().unwrap();
+        assert_eq!(e.message, "Named and positional parameters mixed in one statement");
+    }
+
+    #[test]
+    fn named_params_can_repeat() {
+        match convert_named_params("select :a, :b, :a", named(&["a", "b"])) {
+            Ok(Params::Named(params)) => assert_eq!(params.len(), 2),
+            _ => panic!("expected named params"),
+        }
+    }
+
+    fn columns(names: &[(&str, &str)]) -> Vec<Column> {
+        names.iter().map(|(table, name)| Column::new(ColumnType::MYSQL_TYPE_LONG).with_table(table.as_bytes()).with_name(name.as_bytes())).collect()
+    }
+
+    #[test]
+    fn duplicate_columns_get_suffixes() {
+        assert_eq!(column_keys(&columns(&[("t", "id"), ("u", "id")]), DuplicatePolicy::Suffix).ok().unwrap(), ["id", "id_2"]);
+        assert_eq!(column_keys(&columns(&[("", "id"), ("", "id"), ("", "id_2")]), DuplicatePolicy::Suffix).ok().unwrap(), ["id", "id_3", "id_2"]);
+        assert_eq!(column_keys(&columns(&[("", "a"), ("", "b")]), DuplicatePolicy::Error).ok().unwrap(), ["a", "b"]);
+    }
+
+    #[test]
+    fn duplicate_columns_are_qualified() {
+        assert_eq!(column_keys(&columns(&[("t", "id"), ("u", "id"), ("t", "name")]), DuplicatePolicy::Qualify).ok().unwrap(), ["t.id", "u.id", "name"]);
+        // expressions have no table to qualify them with
+        assert_eq!(column_keys(&columns(&[("t", "id"), ("", "id"), ("", "id")]), DuplicatePolicy::Qualify).ok().unwrap(), ["t.id", "id", "id_2"]);
+        assert_eq!(column_keys(&columns(&[("t", "id"), ("t", "id")]), DuplicatePolicy::Qualify).ok().unwrap(), ["t.id", "t.id_2"]);
+    }
+
+    #[test]
+    fn duplicate_columns_can_be_an_error() {
+        let e = column_keys(&columns(&[("", "b"), ("", "a"), ("", "b"), ("", "a"), ("", "c")]), DuplicatePolicy::Error).err().unwrap();
+        assert_eq!(e.message, "Duplicate column names: a, b");
+        assert_eq!(StatusMap::default().status(&e), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn explicit_format_wins_over_accept() {
+        let format = |format, accept: &str| CallParams { format, ..CallParams::default() }.stream_format(&Some(accept.to_owned()));
+        assert!(matches!(format(None, "text/csv"), Some(StreamFormat::Csv)));
+        assert!(format(Some(ResultFormat::Compact), "text/csv").is_none());
+        assert!(format(Some(ResultFormat::Columnar), "application/vnd.apache.arrow.stream").is_none());
+        assert!(matches!(format(Some(ResultFormat::Compact), "text/csv, application/x-ndjson"), Some(StreamFormat::Ndjson)));
+        assert!(matches!(format(Some(ResultFormat::Tsv), "text/csv"), Some(StreamFormat::Tsv)));
+    }
+
+    #[test]
+    fn parse_time_values() {
+        assert_eq!(parse_time("-838:59:59.5"), Some(mysql_async::Value::Time(true, 34, 22, 59, 59, 500_000)));
+        assert_eq!(parse_time("26:03:04.000005"), Some(mysql_async::Value::Time(false, 1, 2, 3, 4, 5)));
+        assert_eq!(parse_time("00:00:00"), Some(mysql_async::Value::Time(false, 0, 0, 0, 0, 0)));
+    }
+
+    #[test]
+    fn parse_time_rejects_invalid() {
+        for text in ["1:60:00", "12:00", "1:2:3:4", "a:00:00", "00:00:00.1234567", "+1:00:00", ""] {
+            assert_eq!(parse_time(text), None, "{}", text);
+        }
+    }
 }